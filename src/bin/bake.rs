@@ -0,0 +1,45 @@
+// Headless map baking: loads a heightmap image or generates a noise map, erodes it, then saves it
+// as a map cache (.dtmc) or as a 16-bit heightmap image reloadable with the same map options.
+//
+// usage: bake <heightmap image | noise> <output .dtmc or image> [droplets] [seed]
+
+use dynamic_terrain::dt::terrain::{BitDepth, HeightNormalization, Map, MapOptions, MapSource};
+use dynamic_terrain::erosion::erosion::{hydraulic_erosion, HydraulicErosion};
+use dynamic_terrain::error::error::TerrainError;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 5 {
+        eprintln!("usage: {} <heightmap image | noise> <output .dtmc or image> [droplets] [seed]", args[0]);
+        std::process::exit(2);
+    }
+    let mut settings = HydraulicErosion::default();
+    for (index, value) in args.iter().enumerate().skip(3) {
+        let Ok(value) = value.parse() else {
+            eprintln!("invalid number: {}", value);
+            std::process::exit(2);
+        };
+        match index {
+            3 => settings.droplets = value as usize,
+            _ => settings.seed = value,
+        }
+    }
+    if let Err(err) = bake(&args[1], &args[2], &settings) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn bake(input: &str, output: &str, settings: &HydraulicErosion) -> Result<(), TerrainError> {
+    let mut options = MapOptions::default();
+    if input != "noise" {
+        options.source = MapSource::File(input.to_string());
+    }
+    let mut map = Map::new(options)?;
+    hydraulic_erosion(&mut map, settings);
+    if output.ends_with(".dtmc") {
+        map.save_cache(output)
+    } else {
+        map.write_heightmap(output, BitDepth::Sixteen, HeightNormalization::AltitudeFactor)
+    }
+}
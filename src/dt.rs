@@ -1,253 +1,1594 @@
 pub mod terrain {
-    use fastnoise_lite::*;
-
     use crate::ribbon::ribbon::*;
-    use three_d::{vec2, vec3, Context, CpuMaterial, CpuMesh, Gm, Mesh, PhysicalMaterial, Vec2, Vec3};
+    use crate::noise::noise::{NoiseGenerator, NoiseSettings};
+    use crate::source::source::{HeightSource, RayHit, WrapMode};
+    use crate::error::error::TerrainError;
+    use three_d::{vec2, vec3, Context, CpuMaterial, CpuMesh, Gm, InnerSpace, Mesh, PhysicalMaterial, Srgba, Vec2, Vec3, VertexBuffer};
     use std::rc::Rc;
-    use image::ImageReader;
+    use image::{DynamicImage, ImageBuffer, ImageReader, Luma, Pixel};
 
-    // Number of points in the map
-    // set the same value for both WIDTH and HEIGHT
-    const WIDTH: usize = 1280;
-    const HEIGHT: usize = 1280;
+    // Where the map elevation data come from
+    pub enum MapSource {
+        File(String),                                               // heightmap image file
+        Image(DynamicImage),                                        // heightmap image already loaded in memory
+        Noise { width: usize, depth: usize, layers: Vec<NoiseSettings> },   // procedural noise, number of points on the x and z axis
+        Heights { width: usize, depth: usize, data: Vec<f32> },     // raw elevations, row by row, scaled by the altitude factor
+    }
 
-    pub struct Map {
-        pub coords: Vec<Vec<Vec3>>,
-        pub uvs: Vec<Vec2>,
-        pub length: f32,
-        pub subdivisions: usize,
-        pub average_sub_size: f32
+    // How the elevation is stored in a heightmap image
+    pub enum HeightEncoding {
+        Luminance,      // gray level or luminance, at the image precision (8-bit, 16-bit or float), in 0..1 for integer images
+        Mapbox,         // Mapbox Terrain-RGB: -10000 + (R * 256 * 256 + G * 256 + B) * 0.1, in meters
+        Terrarium,      // Terrarium: R * 256 + G + B / 256 - 32768, in meters
+    }
+
+    // Image channel read by the HeightEncoding::Luminance encoding
+    pub enum HeightChannel {
+        Weighted { r: f32, g: f32, b: f32 },    // weighted sum of the red, green and blue channels
+        Red,
+        Green,
+        Blue,
+        Alpha,
+    }
+
+    // Remapping curve applied to the image values before they're scaled to altitudes
+    pub enum HeightCurve {
+        Linear,                             // values used as is
+        Gamma(f32),                         // value^gamma, for values in 0..1
+        Lookup(Vec<f32>),                   // table sampled evenly over 0..1, linearly interpolated
+        Custom(Box<dyn Fn(f32) -> f32>),    // any function of the value
+    }
+
+    impl HeightCurve {
+        pub fn apply(&self, value: f32) -> f32 {
+            match self {
+                HeightCurve::Linear => value,
+                HeightCurve::Gamma(gamma) => value.max(0.0).powf(*gamma),
+                HeightCurve::Lookup(table) => {
+                    if table.len() < 2 {
+                        return table.first().copied().unwrap_or(value);
+                    }
+                    let f = value.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+                    let index = (f as usize).min(table.len() - 2);
+                    let t = f - index as f32;
+                    table[index] * (1.0 - t) + table[index + 1] * t
+                }
+                HeightCurve::Custom(curve) => curve(value),
+            }
+        }
+    }
+
+    // Sample format of the headerless little-endian raw heightfields
+    pub enum RawFormat {
+        R8,             // unsigned 8-bit, normalized in 0..1
+        R16,            // unsigned 16-bit, normalized in 0..1
+        R32,            // 32-bit float, used as is
+    }
+
+    // Bit depth of the exported heightmap images
+    pub enum BitDepth {
+        Eight,
+        Sixteen,
+    }
+
+    // How the altitudes are mapped to the gray levels of the exported heightmap images
+    pub enum HeightNormalization {
+        MinMax,             // the lowest point is black, the highest is white
+        AltitudeFactor,     // inverse of the import transform of the map, clamped to 0..1, fails if the transform isn't invertible
+    }
 
+    // Transform of the source values into the map altitudes: altitude = curve(value) * scale + offset
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct HeightTransform {
+        pub scale: f32,     // altitude factor, or width of the altitude range
+        pub offset: f32,    // altitude of the value 0, sea level and origin included
+        pub curved: bool,   // a non linear height curve was applied before the scaling
+    }
+    impl HeightTransform {
+        // transform of the values of a map source, the heightmap images go through the remapping options, the other sources are only scaled
+        fn from_options(options: &MapOptions, image_source: bool) -> Self {
+            if !image_source {
+                return HeightTransform { scale: options.altitude_factor, offset: options.origin.y, curved: false };
+            }
+            match options.encoding {
+                // the RGB encodings already give altitudes in meters
+                HeightEncoding::Mapbox | HeightEncoding::Terrarium => HeightTransform { scale: 1.0, offset: options.origin.y, curved: false },
+                HeightEncoding::Luminance => {
+                    let (min, scale) = match options.altitude_range {
+                        Some((min, max)) => (min, max - min),
+                        None => (0.0, options.altitude_factor),
+                    };
+                    let curved = !matches!(options.curve, HeightCurve::Linear);
+                    HeightTransform { scale, offset: min - options.sea_level + options.origin.y, curved }
+                }
+            }
+        }
+
+        // scales and offsets a value, the curve isn't part of the transform and must be applied before
+        pub fn apply(&self, value: f32) -> f32 {
+            value * self.scale + self.offset
+        }
+
+        // transform of the altitudes back into the source values
+        // the curves and the zero scales lose the source values, so they can't be inverted
+        pub fn inverse(&self) -> Result<HeightTransform, TerrainError> {
+            if self.curved {
+                return Err(TerrainError::NonInvertibleHeights("a height curve was applied"));
+            }
+            if self.scale == 0.0 || !self.scale.is_finite() {
+                return Err(TerrainError::NonInvertibleHeights("the altitude scale is zero"));
+            }
+            Ok(HeightTransform { scale: 1.0 / self.scale, offset: -self.offset / self.scale, curved: false })
+        }
+    }
+
+    // Map construction parameters
+    pub struct MapOptions {
+        pub source: MapSource,
+        pub spacing: Vec2,              // distance between two successive map points on the x axis (x) and on the z axis (y)
+        pub altitude_factor: f32,       // elevation scale applied to the source values
+        pub encoding: HeightEncoding,   // elevation encoding of the heightmap images
+        pub channel: HeightChannel,     // image channel read by the luminance encoding
+        pub curve: HeightCurve,         // remapping of the luminance heightmap values, before the scaling
+        pub altitude_range: Option<(f32, f32)>, // altitudes (min, max) of the image values 0 and 1, replaces the altitude factor for the luminance heightmaps
+        pub sea_level: f32,             // altitude subtracted from the luminance heightmap altitudes, so that the sea level is at the origin
+        pub origin: Vec3,               // world position of the map center
+        pub compute_normals: bool,      // precompute the normal of each map point at load time
+        pub color_file: Option<String>, // image of the same size than the map giving the color of each map point
+        pub wrap: WrapMode,             // how the map is extended beyond its edges
+    }
+    impl Default for MapOptions {
+        fn default() -> Self {
+            MapOptions {
+                source: MapSource::Noise { width: 1280, depth: 1280, layers: vec![NoiseSettings::default()] },
+                spacing: vec2(5.0, 5.0),
+                altitude_factor: 80.0,
+                encoding: HeightEncoding::Luminance,
+                channel: HeightChannel::Weighted { r: 0.3, g: 0.59, b: 0.11 },
+                curve: HeightCurve::Linear,
+                altitude_range: None,
+                sea_level: 0.0,
+                origin: vec3(0.0, 0.0, 0.0),
+                compute_normals: false,
+                color_file: None,
+                wrap: WrapMode::Repeat,
+            }
+        }
+    }
+
+    pub struct Map {
+        pub heights: Vec<f32>,      // elevation of each map point, row by row, the x and z coordinates are given by the grid indexes
+        pub normals: Vec<Vec3>,     // empty if not precomputed
+        pub colors: Vec<Srgba>,     // color of each map point, row by row like the heights, empty if not set
+        pub width: usize,           // number of points on the x axis
+        pub depth: usize,           // number of points on the z axis
+        pub spacing: Vec2,          // distance between two successive map points on the x axis (x) and on the z axis (y)
+        pub transform: HeightTransform, // transform of the source values into the elevations, the map was built with
+        pub origin: Vec3,           // world position of the map center
+        pub wrap: WrapMode,         // how the map is extended beyond its edges
     }
     impl Map {
 
-        pub fn new() -> Self {
-            //let (coords, uvs) = Self::create_map();
-            let (coords, uvs) = Self::create_heightmap_from_file("assets/worldHeightMapDouble.png", 5.0, 80.0);
-            let l = coords.len();
-            let length = (coords[0][l - 1].x - coords[0][0].x).abs();
-            let average_sub_size = length / l as f32;
-            Map {
-                coords,
-                uvs,
-                length,
-                subdivisions: l,
-                average_sub_size
-            }
-        }
-        
-        pub fn create_map() -> (Vec<Vec<Vec3>>, Vec<Vec2>) {
-            let scl_x = 5.0;
-            let scl_y: f32 = 100.0;
-            let scl_z = scl_x;
-            let hw = WIDTH as f32 * 0.5;
-            let hh = HEIGHT as f32 * 0.5;
-            let noise_data = Self::create_noise();
-            let mut paths = Vec::new();
-            for j in 0..WIDTH {
-               let mut path = Vec::new();
-               for i in 0..HEIGHT {
-                    let x = (i as f32 - hw) * scl_x;
-                    let y = noise_data[j][i] * scl_y * ((i as f32 + j as f32) * 0.01).sin();
-                    let z = (j as f32 - hh) * scl_z;
-                   path.push(vec3(x, y, z));
-               }
-               paths.push(path);
-            }
-            let uvs = Vec::new();
-            (paths, uvs)
-        }
-
-        pub fn create_noise() -> Vec<Vec<f32>> {
-            let mut noise = FastNoiseLite::new();
-            noise.set_noise_type(Some(NoiseType::OpenSimplex2));
-            noise.set_seed(Some(20));
-            noise.set_frequency(Some(0.015));
-            
-            let mut noise_data = vec![vec![0.; HEIGHT]; WIDTH];
-           
-            // Sample noise pixels
-            for x in 0..WIDTH {
-                for y in 0..HEIGHT {
-                    // Domain warp can optionally be employed to transform the coordinates before sampling:
-                    // let (x, y) = noise.domain_warp_2d(x as f32, y as f32);
-                    
-                    let negative_1_to_1 = noise.get_noise_2d(x as f32, y as f32);
-                    // You may want to remap the -1..1 range data to the 0..1 range:
-                    noise_data[x][y] = (negative_1_to_1 + 1.) / 2.;                   
-                }
-            }
-            noise_data
-        }
-
-        // create a ribbon mesh from the map
-        pub fn create_mesh(&self, coords: &Vec<Vec<Vec3>>, uvs: &Vec<Vec2>) -> CpuMesh {
-            let ribbon = create_ribbon(&coords, &uvs);
-            ribbon.into()
+        pub fn new(mut options: MapOptions) -> Result<Self, TerrainError> {
+            // the source is taken out of the options, so that the raw elevations are moved into the map instead of copied
+            let source = std::mem::replace(&mut options.source, MapSource::Heights { width: 0, depth: 0, data: Vec::new() });
+            let transform = HeightTransform::from_options(&options, matches!(source, MapSource::File(_) | MapSource::Image(_)));
+            let (width, depth, mut heights) = match source {
+                MapSource::File(file) => Self::create_heightmap_from_file(&file, &options)?,
+                MapSource::Image(img) => Self::create_heightmap_from_image(&img, &options),
+                MapSource::Noise { width, depth, layers } => (width, depth, Self::create_map(width, depth, &layers)),
+                MapSource::Heights { width, depth, data } => {
+                    if data.len() != width * depth {
+                        return Err(TerrainError::SizeMismatch { expected: width * depth, found: data.len() });
+                    }
+                    (width, depth, data)
+                }
+            };
+            Self::check_dimensions(width, depth)?;
+            for height in heights.iter_mut() {
+                let value = if transform.curved { options.curve.apply(*height) } else { *height };
+                *height = transform.apply(value);
+            }
+            let mut map = Map {
+                heights,
+                normals: Vec::new(),
+                colors: Vec::new(),
+                width,
+                depth,
+                spacing: options.spacing,
+                transform,
+                origin: options.origin,
+                wrap: options.wrap,
+            };
+            if options.compute_normals {
+                map.compute_normals();
+            }
+            if let Some(color_file) = &options.color_file {
+                map.load_colors_from_file(color_file)?;
+            }
+            Ok(map)
+        }
+
+        // index in the heights of the cell (i, j) wrapped like the map, None if the cell is beyond the edges of a clamped map
+        pub fn cell_index(&self, i: i32, j: i32) -> Option<usize> {
+            let (wi, wj) = self.wrap_cell(i, j);
+            (self.wrap == WrapMode::Repeat || (wi, wj) == (i, j)).then_some(wi as usize * self.width + wj as usize)
+        }
+
+        // elevation of the map point i on the z axis and j on the x axis
+        pub fn height(&self, i: usize, j: usize) -> f32 {
+            self.heights[i * self.width + j]
+        }
+
+        // coordinates of the map point i on the z axis and j on the x axis, the map being centered on its origin
+        pub fn point(&self, i: usize, j: usize) -> Vec3 {
+            let x = (j as f32 - self.width as f32 * 0.5) * self.spacing.x + self.origin.x;
+            let z = (i as f32 - self.depth as f32 * 0.5) * self.spacing.y + self.origin.z;
+            vec3(x, self.heights[i * self.width + j], z)
+        }
+
+        // texture coordinates of the map point i on the z axis and j on the x axis
+        pub fn uv(&self, i: usize, j: usize) -> Vec2 {
+            vec2(j as f32 / self.width as f32, 1.0 - i as f32 / self.depth as f32)
+        }
+
+        // loads a map from a headerless little-endian heightfield (.r16, .r32, .raw) of width x depth samples, row by row
+        // the samples are scaled by "scale" and the map points are "spacing" apart, like the heightmap images
+        pub fn from_raw(path: &str, width: usize, depth: usize, format: RawFormat, spacing: Vec2, scale: f32) -> Result<Self, TerrainError> {
+            let bytes = std::fs::read(path)?;
+            let sample_size = match format {
+                RawFormat::R8 => 1,
+                RawFormat::R16 => 2,
+                RawFormat::R32 => 4,
+            };
+            if bytes.len() != width * depth * sample_size {
+                return Err(TerrainError::SizeMismatch { expected: width * depth * sample_size, found: bytes.len() });
+            }
+            let data = match format {
+                RawFormat::R8 => bytes.iter().map(|b| *b as f32 / 255.0).collect(),
+                RawFormat::R16 => bytes.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]]) as f32 / 65535.0).collect(),
+                RawFormat::R32 => bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+            };
+            Self::new(MapOptions {
+                source: MapSource::Heights { width, depth, data },
+                spacing,
+                altitude_factor: scale,
+                ..Default::default()
+            })
+        }
+
+        // writes the map elevations as a headerless little-endian heightfield, row by row, inverse of from_raw()
+        // the integer formats are clamped to the 0..1 range once the map transform is inverted, which fails if it isn't invertible
+        pub fn write_raw(&self, path: &str, format: RawFormat) -> Result<(), TerrainError> {
+            let inverse = self.transform.inverse()?;
+            let mut bytes = Vec::new();
+            for height in &self.heights {
+                let value = inverse.apply(*height);
+                match format {
+                    RawFormat::R8 => bytes.push((value.clamp(0.0, 1.0) * 255.0).round() as u8),
+                    RawFormat::R16 => bytes.extend_from_slice(&((value.clamp(0.0, 1.0) * 65535.0).round() as u16).to_le_bytes()),
+                    RawFormat::R32 => bytes.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+            std::fs::write(path, bytes)?;
+            Ok(())
+        }
+
+        // saves the map in the binary cache format, reloaded with Map::load_cache() much faster than decoding the source image
+        // layout, little-endian: magic "DTMC", version, width, depth, spacing x and z, transform scale and offset, origin x, y and z,
+        // flags (1 normals, 2 colors, 4 clamped edges, 8 curved transform), the f32 elevations row by row, then the optional normals (3 x f32) and colors (4 x u8),
+        // ended by the FNV-1a 64 checksum of all the previous bytes
+        pub fn save_cache(&self, path: &str) -> Result<(), TerrainError> {
+            let mut bytes = Vec::with_capacity(48 + self.width * self.depth * 4);
+            bytes.extend_from_slice(CACHE_MAGIC);
+            bytes.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+            bytes.extend_from_slice(&(self.width as u32).to_le_bytes());
+            bytes.extend_from_slice(&(self.depth as u32).to_le_bytes());
+            for value in [self.spacing.x, self.spacing.y, self.transform.scale, self.transform.offset, self.origin.x, self.origin.y, self.origin.z] {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            let has_normals = !self.normals.is_empty();
+            let has_colors = !self.colors.is_empty();
+            let clamped = self.wrap == WrapMode::Clamp;
+            let flags = has_normals as u32 | (has_colors as u32) << 1 | (clamped as u32) << 2 | (self.transform.curved as u32) << 3;
+            bytes.extend_from_slice(&flags.to_le_bytes());
+            for height in &self.heights {
+                bytes.extend_from_slice(&height.to_le_bytes());
+            }
+            if has_normals {
+                for n in &self.normals {
+                    for value in [n.x, n.y, n.z] {
+                        bytes.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+            if has_colors {
+                for c in &self.colors {
+                    bytes.extend_from_slice(&[c.r, c.g, c.b, c.a]);
+                }
+            }
+            let checksum = fnv1a(&bytes);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+            std::fs::write(path, bytes)?;
+            Ok(())
+        }
+
+        // loads a map saved by Map::save_cache(), the caches of another format version or with a wrong checksum are rejected
+        pub fn load_cache(path: &str) -> Result<Self, TerrainError> {
+            let bytes = std::fs::read(path)?;
+            if bytes.len() < 8 + 8 || &bytes[0..4] != CACHE_MAGIC {
+                return Err(TerrainError::InvalidCache("bad magic number"));
+            }
+            let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            if version != CACHE_VERSION {
+                return Err(TerrainError::CacheVersion { expected: CACHE_VERSION, found: version });
+            }
+            let (content, checksum) = bytes.split_at(bytes.len() - 8);
+            if fnv1a(content).to_le_bytes() != checksum {
+                return Err(TerrainError::InvalidCache("checksum mismatch"));
+            }
+            let mut reader = CacheReader { bytes: content, offset: 8 };
+            let width = reader.read_u32()? as usize;
+            let depth = reader.read_u32()? as usize;
+            let spacing = vec2(reader.read_f32()?, reader.read_f32()?);
+            let transform = HeightTransform { scale: reader.read_f32()?, offset: reader.read_f32()?, curved: false };
+            let origin = vec3(reader.read_f32()?, reader.read_f32()?, reader.read_f32()?);
+            let flags = reader.read_u32()?;
+            let nb_points = width * depth;
+            let data = (0..nb_points).map(|_| reader.read_f32()).collect::<Result<Vec<f32>, TerrainError>>()?;
+            let normals = if flags & 1 != 0 {
+                (0..nb_points).map(|_| Ok(vec3(reader.read_f32()?, reader.read_f32()?, reader.read_f32()?))).collect::<Result<Vec<Vec3>, TerrainError>>()?
+            } else {
+                Vec::new()
+            };
+            let colors = if flags & 2 != 0 {
+                (0..nb_points).map(|_| {
+                    let c = reader.read_bytes(4)?;
+                    Ok(Srgba::new(c[0], c[1], c[2], c[3]))
+                }).collect::<Result<Vec<Srgba>, TerrainError>>()?
+            } else {
+                Vec::new()
+            };
+            if reader.offset != content.len() {
+                return Err(TerrainError::InvalidCache("unexpected trailing data"));
+            }
+            // the elevations are stored as is, so they're rebuilt with a unit transform
+            let mut map = Self::new(MapOptions {
+                source: MapSource::Heights { width, depth, data },
+                spacing,
+                altitude_factor: 1.0,
+                origin: vec3(origin.x, 0.0, origin.z),
+                wrap: if flags & 4 != 0 { WrapMode::Clamp } else { WrapMode::Repeat },
+                ..Default::default()
+            })?;
+            map.transform = HeightTransform { curved: flags & 8 != 0, ..transform };
+            map.origin = origin;
+            map.normals = normals;
+            map.colors = colors;
+            Ok(map)
+        }
+
+        // saves the map elevations as a grayscale heightmap image, the format is given by the file extension
+        // with HeightNormalization::AltitudeFactor, the image can be reloaded with MapSource::File and the same options,
+        // as long as the map transform is invertible
+        pub fn write_heightmap(&self, path: &str, bit_depth: BitDepth, normalization: HeightNormalization) -> Result<(), TerrainError> {
+            let to_gray = match normalization {
+                HeightNormalization::MinMax => {
+                    let (min, max) = self.heights.iter().fold((f32::MAX, f32::MIN), |(min, max), height| (min.min(*height), max.max(*height)));
+                    let range = max - min;
+                    let scale = if range > 0.0 { 1.0 / range } else { 0.0 };
+                    HeightTransform { scale, offset: -min * scale, curved: false }
+                }
+                HeightNormalization::AltitudeFactor => self.transform.inverse()?,
+            };
+            let gray = |i: u32, j: u32| to_gray.apply(self.height(j as usize, i as usize)).clamp(0.0, 1.0);
+            let result = match bit_depth {
+                BitDepth::Eight => ImageBuffer::from_fn(self.width as u32, self.depth as u32, |i, j| Luma([(gray(i, j) * 255.0).round() as u8])).save(path),
+                BitDepth::Sixteen => ImageBuffer::from_fn(self.width as u32, self.depth as u32, |i, j| Luma([(gray(i, j) * 65535.0).round() as u16])).save(path),
+            };
+            result.map_err(TerrainError::Encode)
+        }
+
+        // sets the color of each map point, row by row like the heights
+        pub fn set_colors(&mut self, colors: Vec<Srgba>) -> Result<(), TerrainError> {
+            if colors.len() != self.heights.len() {
+                return Err(TerrainError::SizeMismatch { expected: self.heights.len(), found: colors.len() });
+            }
+            self.colors = colors;
+            Ok(())
+        }
+
+        // loads the color of each map point from an image of the same size than the map
+        pub fn load_colors_from_file(&mut self, file: &str) -> Result<(), TerrainError> {
+            let img = ImageReader::open(file)?.decode()?.to_rgba8();
+            let (width, height) = (img.width() as usize, img.height() as usize);
+            if width != self.width || height != self.depth {
+                return Err(TerrainError::DimensionMismatch { expected: (self.width, self.depth), found: (width, height) });
+            }
+            self.colors = img.pixels().map(|p| Srgba::new(p[0], p[1], p[2], p[3])).collect();
+            Ok(())
+        }
+
+        // computes the color of each map point from its coordinates
+        pub fn compute_colors(&mut self, color: impl Fn(Vec3) -> Srgba) {
+            self.colors = (0..self.depth).flat_map(|i| (0..self.width).map(move |j| (i, j))).map(|(i, j)| color(self.point(i, j))).collect();
+        }
+
+        // computes the normal of each map point, stored row by row like the heights
+        // a repeated map wraps around at its edges, so the first and last rows and columns are neighbours
+        // the normals point up, with the same orientation than the ones of a ribbon built from the map coordinates
+        pub fn compute_normals(&mut self) {
+            let (width, depth) = (self.width, self.depth);
+            let sx = self.spacing.x;
+            let sz = self.spacing.y;
+            let mut normals = vec![vec3(0.0, 0.0, 0.0); width * depth];
+            let (nb_quads_x, nb_quads_z) = match self.wrap {
+                WrapMode::Repeat => (width, depth),
+                WrapMode::Clamp => (width - 1, depth - 1),
+            };
+            for i in 0..nb_quads_z {
+                let ni = (i + 1) % depth;
+                for j in 0..nb_quads_x {
+                    let nj = (j + 1) % width;
+                    // quad corners relative to the point (i, j)
+                    let p00 = vec3(0.0, self.height(i, j), 0.0);
+                    let p01 = vec3(sx, self.height(i, nj), 0.0);
+                    let p10 = vec3(0.0, self.height(ni, j), sz);
+                    let p11 = vec3(sx, self.height(ni, nj), sz);
+                    let i0 = i * width + j;
+                    let i1 = i * width + nj;
+                    let j0 = ni * width + j;
+                    let j1 = ni * width + nj;
+                    // same triangles than create_ribbon()
+                    let n = (p11 - p00).cross(p01 - p00);
+                    normals[i0] += n;
+                    normals[i1] += n;
+                    normals[j1] += n;
+                    let n = (p00 - p11).cross(p10 - p11);
+                    normals[j1] += n;
+                    normals[j0] += n;
+                    normals[i0] += n;
+                }
+            }
+            for n in normals.iter_mut() {
+                *n = n.normalize();
+            }
+            self.normals = normals;
+        }
+
+        // a map is a grid of at least 2x2 points
+        fn check_dimensions(width: usize, depth: usize) -> Result<(), TerrainError> {
+            if width < 2 || depth < 2 {
+                return Err(TerrainError::InvalidDimensions { width, height: depth });
+            }
+            Ok(())
+        }
+
+        // computes the elevations of a noise map, row by row, from the stacked noise layers
+        pub fn create_map(width: usize, depth: usize, layers: &[NoiseSettings]) -> Vec<f32> {
+            let generator = NoiseGenerator::new(layers);
+            let mut elevations = vec![0.0; width * depth];
+            for (j, row) in elevations.chunks_mut(width).enumerate() {
+                for (i, elevation) in row.iter_mut().enumerate() {
+                    *elevation = generator.get_noise(i as f32, j as f32);
+                }
+            }
+            elevations
+        }
+
+        // create a ribbon mesh from map coordinates and uvs, like the ones given by Map::point() and Map::uv()
+        pub fn create_mesh(coords: &[Vec<Vec3>], uvs: &[Vec2]) -> Result<CpuMesh, TerrainError> {
+            create_ribbon(coords, uvs)
+        }
+
+
+        // create a ribbon mesh from the sub-rectangle of nb_x x nb_z map points starting at the point (start_i on z, start_j on x)
+        // used to export a part of the map
+        pub fn create_region_mesh(&self, start_i: usize, start_j: usize, nb_z: usize, nb_x: usize) -> Result<CpuMesh, TerrainError> {
+            let width = self.width;
+            // the region must fit in the rows and then in the columns left after its start point
+            if start_i + nb_z > self.depth {
+                return Err(TerrainError::SizeMismatch { expected: self.depth.saturating_sub(start_i), found: nb_z });
+            }
+            if start_j + nb_x > width {
+                return Err(TerrainError::SizeMismatch { expected: width.saturating_sub(start_j), found: nb_x });
+            }
+            let paths: Vec<Vec<Vec3>> = (start_i..start_i + nb_z).map(|i| (start_j..start_j + nb_x).map(|j| self.point(i, j)).collect()).collect();
+            let uvs: Vec<Vec2> = (start_i..start_i + nb_z).flat_map(|i| (start_j..start_j + nb_x).map(move |j| (i, j))).map(|(i, j)| self.uv(i, j)).collect();
+            create_ribbon(&paths, &uvs)
         }
 
 
         // idée : stocker les données dans un fichier 
         // https://docs.rs/image/latest/image/type.RgbImage.html
-        pub fn create_heightmap_from_file(file: &str, offset: f32, altitude_factor: f32) -> (Vec<Vec<Vec3>>, Vec<Vec2>) {
-            let filter_r = 0.3;
-            let filter_g = 0.59;
-            let filter_b = 0.11;
-            let dyn_img = ImageReader::open(file).unwrap().decode().unwrap();
-            let img = dyn_img.into_rgb8();
-            let half_width = img.width() as f32 * 0.5;
-            let half_height = img.height() as f32 * 0.5;
-            let mut data = Vec::new();
-            let mut uvs = Vec::new();
-            img.enumerate_rows().for_each(|(j, row)| {
-                let mut path = Vec::new();
-                row.enumerate().for_each(|(i, pixel)| {
-                    let rgb = pixel.2   ;
-                    let r = rgb[0] as f32;
-                    let g = rgb[1] as f32;  
-                    let b = rgb[2] as f32;
-                    let gradient = (r * filter_r + g * filter_g + b * filter_b) / 255.0;
-                    let altitude = gradient * altitude_factor;
-                    let x = (i as f32 - half_width) * offset;
-                    let z = (j as f32 - half_height) * offset;
-                    let v3 = vec3(x, altitude, z);
-                    let u = i as f32 / img.width() as f32;
-                    let v = 1.0 - j as f32 / img.height() as f32;
-                    let uv = vec2(u, v);
-                    path.push(v3);
-                    uvs.push(uv);
-                });
-                data.push(path);
-            });
-
-            
-            (data, uvs)
+        // returns the width, depth and elevations of the image, row by row
+        pub fn create_heightmap_from_file(file: &str, options: &MapOptions) -> Result<(usize, usize, Vec<f32>), TerrainError> {
+            let dyn_img = ImageReader::open(file)?.decode()?;
+            Ok(Self::create_heightmap_from_image(&dyn_img, options))
+        }
+
+        pub fn create_heightmap_from_image(dyn_img: &DynamicImage, options: &MapOptions) -> (usize, usize, Vec<f32>) {
+            let (width, depth) = (dyn_img.width() as usize, dyn_img.height() as usize);
+            let elevations: Vec<f32> = match options.encoding {
+                HeightEncoding::Luminance => Self::image_values(dyn_img, |rgba| match options.channel {
+                    HeightChannel::Weighted { r, g, b } => rgba[0] * r + rgba[1] * g + rgba[2] * b,
+                    HeightChannel::Red => rgba[0],
+                    HeightChannel::Green => rgba[1],
+                    HeightChannel::Blue => rgba[2],
+                    HeightChannel::Alpha => rgba[3],
+                }),
+                HeightEncoding::Mapbox | HeightEncoding::Terrarium => Self::image_values(dyn_img, |rgba| {
+                    // the RGB encodings are 8-bit, the bytes are restored from the normalized channels
+                    let r = (rgba[0] * 255.0).round() as f64;
+                    let g = (rgba[1] * 255.0).round() as f64;
+                    let b = (rgba[2] * 255.0).round() as f64;
+                    let altitude = match options.encoding {
+                        HeightEncoding::Mapbox => -10000.0 + (r * 65536.0 + g * 256.0 + b) * 0.1,
+                        _ => r * 256.0 + g + b / 256.0 - 32768.0,
+                    };
+                    altitude as f32
+                }),
+            };
+            (width, depth, elevations)
+        }
+
+        // value of each pixel computed from its channels normalized in 0..1, the pixels are read in place in their own format,
+        // keeping the 16-bit and float precision without converting the whole image
+        fn image_values(dyn_img: &DynamicImage, value: impl Fn([f32; 4]) -> f32) -> Vec<f32> {
+            match dyn_img {
+                DynamicImage::ImageLuma8(img) => Self::pixel_values(img, 1.0 / 255.0, value),
+                DynamicImage::ImageLumaA8(img) => Self::pixel_values(img, 1.0 / 255.0, value),
+                DynamicImage::ImageRgb8(img) => Self::pixel_values(img, 1.0 / 255.0, value),
+                DynamicImage::ImageRgba8(img) => Self::pixel_values(img, 1.0 / 255.0, value),
+                DynamicImage::ImageLuma16(img) => Self::pixel_values(img, 1.0 / 65535.0, value),
+                DynamicImage::ImageLumaA16(img) => Self::pixel_values(img, 1.0 / 65535.0, value),
+                DynamicImage::ImageRgb16(img) => Self::pixel_values(img, 1.0 / 65535.0, value),
+                DynamicImage::ImageRgba16(img) => Self::pixel_values(img, 1.0 / 65535.0, value),
+                DynamicImage::ImageRgb32F(img) => Self::pixel_values(img, 1.0, value),
+                DynamicImage::ImageRgba32F(img) => Self::pixel_values(img, 1.0, value),
+                // formats added by later versions of the image crate
+                _ => Self::pixel_values(&dyn_img.to_rgba32f(), 1.0, value),
+            }
+        }
+
+        // normalized channels of each pixel given to the value function
+        fn pixel_values<P>(img: &ImageBuffer<P, Vec<P::Subpixel>>, scale: f32, value: impl Fn([f32; 4]) -> f32) -> Vec<f32>
+        where
+            P: Pixel,
+            P::Subpixel: Into<f32>,
+        {
+            img.pixels().map(|pixel| {
+                let rgba = pixel.to_rgba();
+                value([rgba[0].into() * scale, rgba[1].into() * scale, rgba[2].into() * scale, rgba[3].into() * scale])
+            }).collect()
         }
     }
 
 
 
-    pub struct Terrain {
-        pub map: Rc<Map>,
+    // the map is extended beyond its edges according to its wrap mode
+    impl HeightSource for Map {
+        fn height_at_cell(&self, i: i32, j: i32) -> f32 {
+            let (i, j) = self.wrap_cell(i, j);
+            self.height(i as usize, j as usize)
+        }
+
+        fn uv_at_cell(&self, i: i32, j: i32) -> Vec2 {
+            let (i, j) = self.wrap_cell(i, j);
+            self.uv(i as usize, j as usize)
+        }
+
+        fn spacing(&self) -> Vec2 {
+            self.spacing
+        }
+
+        fn cell_origin(&self) -> Vec2 {
+            let corner = self.point(0, 0);
+            vec2(corner.x, corner.z)
+        }
+
+        fn dimensions(&self) -> Option<(usize, usize)> {
+            Some((self.width, self.depth))
+        }
+
+        fn wrap_mode(&self) -> WrapMode {
+            self.wrap
+        }
+
+        fn has_normals(&self) -> bool {
+            !self.normals.is_empty()
+        }
+
+        fn normal_at_cell(&self, i: i32, j: i32) -> Vec3 {
+            let (i, j) = self.wrap_cell(i, j);
+            self.normals[i as usize * self.width + j as usize]
+        }
+
+        fn has_colors(&self) -> bool {
+            !self.colors.is_empty()
+        }
+
+        fn color_at_cell(&self, i: i32, j: i32) -> Srgba {
+            let (i, j) = self.wrap_cell(i, j);
+            self.colors[i as usize * self.width + j as usize]
+        }
+    }
+
+    // map cache format identification
+    const CACHE_MAGIC: &[u8; 4] = b"DTMC";
+    const CACHE_VERSION: u32 = 3;
+
+    // FNV-1a 64 bits hash, used as the map cache checksum
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    // sequential reader of the map cache content
+    struct CacheReader<'a> {
+        bytes: &'a [u8],
+        offset: usize,
+    }
+
+    impl CacheReader<'_> {
+        fn read_bytes(&mut self, count: usize) -> Result<&[u8], TerrainError> {
+            if self.offset + count > self.bytes.len() {
+                return Err(TerrainError::InvalidCache("truncated data"));
+            }
+            let bytes = &self.bytes[self.offset..self.offset + count];
+            self.offset += count;
+            Ok(bytes)
+        }
+
+        fn read_u32(&mut self) -> Result<u32, TerrainError> {
+            let b = self.read_bytes(4)?;
+            Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        }
+
+        fn read_f32(&mut self) -> Result<f32, TerrainError> {
+            let b = self.read_bytes(4)?;
+            Ok(f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        }
+    }
+
+    // blending of a vertex height towards the coarser LOD level along a terrain axis
+    #[derive(Clone, Copy)]
+    struct MorphWeight {
+        t: f32,         // blend factor, 0 at the band start, 1 at the LOD limit
+        a: i32,         // map offset of the coarse lattice point before the vertex
+        b: i32,         // map offset of the coarse lattice point after the vertex
+        w: f32,         // position of the vertex between a and b
+    }
+
+    // terrain heights before a change of the global stride
+    struct GlobalMorph {
+        cells_x: Vec<i32>,          // cells of the vertices on the x axis, counted from the initial center cell
+        cells_z: Vec<i32>,          // cells of the vertices on the z axis, counted from the initial center cell
+        heights: Vec<Vec<f32>>,     // vertex heights, path by path
+        frame: usize,               // updates since the change
+    }
+
+    // vertex data passed to the custom vertex function of the terrain
+    pub struct VertexData {
+        pub position: Vec3,     // mesh coordinates
+        pub uv: Vec2,
+        pub color: Srgba,
+    }
+
+    // custom vertex function, called with the vertex data and the source cell (map_i on z, map_j on x) it's sampled from,
+    // wrapped into the source data
+    pub type UpdateVertexFn = dyn FnMut(&mut VertexData, i32, i32);
+
+    // vertices of the terrain sampled from its height source around the camera
+    // this part of the terrain needs no rendering context, the mesh buffers are filled from it by Terrain
+    pub struct TerrainSampler {
+        pub source: Rc<dyn HeightSource>,  // map or procedural source sampled by the terrain
         pub size: usize,            // nb of cells in the terrain edge
-        pub length: f32,            // length of the terrain edge
-        pub cpu_mesh: CpuMesh,
-        pub cpu_material: CpuMaterial,
-        pub mesh: Gm<Mesh, PhysicalMaterial>,
+        pub length: Vec2,           // length of the terrain edges on the x axis (x) and on the z axis (y)
         pub paths: Vec<Vec<Vec3>>,
         pub uvs: Vec<Vec2>,
-        pub position: Vec3,       // mesh logical coordinates
-        pub sub_tolerance: i32,   // how many cells flyable over by the camera on the terrain axis before trigger an update
+        pub normals: Vec<Vec3>,     // copied from the source or computed from the vertices, empty if neither is done
+        pub colors: Vec<Srgba>,     // vertex colors, empty unless the source has colors or a custom vertex function is set
+        pub position: Vec3,       // world position of the map point under the terrain center vertex, moved by whole scrolled cells only
+        pub sub_tolerance: i32,   // how many cells flyable over by the camera on the terrain axis before trigger an update, the steps being fixed to the initial center
         pub camera_pos: Vec3,
+        pub compute_normals: bool, // recompute the normals and tangents each time the mesh is updated, unless the source has precomputed normals and no vertex was moved
+        pub lod_limits: Vec<usize>, // distances in quads from the terrain center from which the map sampling stride is multiplied by lod_factor
+        pub lod_factor: usize,      // stride multiplier applied at each LOD limit
+        pub lod_camera_altitude: Option<Box<dyn Fn(f32) -> usize>>,  // global stride multiplier from the camera altitude
+        pub lod_morph_band: usize,  // width in quads before each LOD limit where the heights blend towards the coarser level, 0 to disable
+        pub lod_global_morph_frames: usize, // number of updates over which the heights blend into the new ones after a change of the global stride, 0 to disable
+        pub update_vertex: Option<Box<UpdateVertexFn>>,     // custom vertex function called for each vertex by sample()
+        lod_global: usize,        // current global stride multiplier
+        global_morph: Option<GlobalMorph>,  // heights before the last change of the global stride, while they're blended into the new ones
+        center: Vec3,             // mesh coordinates of the terrain center vertex
         delta_sub_x: i32,         // how many cells flought over thy the camera on the terrain x axis 
         delta_sub_z: i32,         // how many cells flought over thy the camera on the terrain x axis 
+        scrolled_x: i32,          // cells scrolled on the x axis since the creation, not wrapped, the position is computed from it
+        scrolled_z: i32,          // cells scrolled on the z axis since the creation, not wrapped
     }
-    impl Terrain {
-        pub fn new(context: &Context, map: Rc<Map>, size: usize, cpu_material: CpuMaterial) -> Self {
-            let (cpu_mesh, paths, uvs) = Self::create_cpu_mesh(&map.coords, &map.uvs, size);
+    impl TerrainSampler {
+        pub fn new(source: Rc<dyn HeightSource>, size: usize) -> Result<Self, TerrainError> {
             let ht = (size as f32 * 0.5) as usize;                      // half size of the terrain in quads
-            let hm = (map.subdivisions as f32 * 0.5) as usize;          // half size of the map in quads
-            let terrain_index = hm - ht;                                // index of the first quad of the terrain in the map
-            let length = (map.coords[0][terrain_index + size - 1].x - map.coords[0][terrain_index].x).abs();    // length of the terrain edge
-            let material = PhysicalMaterial::new_transparent(&context, &cpu_material);
-            let mesh = Gm::new(Mesh::new(&context, &cpu_mesh), material);
-            // initial terrain coordinates 
-            let x = map.coords[terrain_index][terrain_index].x + length * 0.5;
-            let z = map.coords[terrain_index][terrain_index].z + length * 0.5;
-            let position = vec3(x, 0.0, z);
-            // initial deltas of the terrain in the map
-            let delta_nb_sub_x = (x - map.coords[0][0].x) / map.average_sub_size as f32;
-            let delta_nb_sub_z = (z - map.coords[0][0].z) / map.average_sub_size as f32;
-            let delta_sub_x = if delta_nb_sub_x > 0.0 { delta_nb_sub_x as i32 } else { delta_nb_sub_x as i32 + 1 };
-            let delta_sub_z = if delta_nb_sub_z > 0.0 { delta_nb_sub_z as i32 } else { delta_nb_sub_z as i32 + 1 };
-            Terrain {
-                map,
+            // a finite source starts at its center and must be larger than the terrain, an endless one starts at the cell (0, 0)
+            let (hm_x, hm_z) = match source.dimensions() {
+                Some((width, depth)) => {
+                    let hm_x = (width as f32 * 0.5) as usize;           // half size of the map in quads on the x axis
+                    let hm_z = (depth as f32 * 0.5) as usize;           // half size of the map in quads on the z axis
+                    if size == 0 || ht > hm_x.min(hm_z) || hm_x - ht + size >= width || hm_z - ht + size >= depth {
+                        return Err(TerrainError::MapSmallerThanTerrain { map_size: width.min(depth), terrain_size: size });
+                    }
+                    (hm_x, hm_z)
+                }
+                None => (0, 0),
+            };
+            let nb_vertices = size + 1;
+            let spacing = source.spacing();
+            let length = vec2(size as f32 * spacing.x, size as f32 * spacing.y);    // length of the terrain edges
+            // initial terrain coordinates, at the map point under the terrain center vertex
+            let cell_origin = source.cell_origin();
+            let center = vec3(cell_origin.x + hm_x as f32 * spacing.x, 0.0, cell_origin.y + hm_z as f32 * spacing.y);
+            let position = center;
+            // initial deltas of the terrain in the map: indexes of the map point under the terrain center vertex
+            let delta_sub_x = hm_x as i32;
+            let delta_sub_z = hm_z as i32;
+            let mut sampler = TerrainSampler {
+                source,
                 size,
                 length,
-                cpu_mesh,
-                cpu_material,
-                mesh,
-                paths,
-                uvs,
+                paths: vec![vec![vec3(0.0, 0.0, 0.0); nb_vertices]; nb_vertices],
+                uvs: vec![vec2(0.0, 0.0); nb_vertices * nb_vertices],
+                normals: Vec::new(),
+                colors: Vec::new(),
                 position,
                 sub_tolerance: 1,
                 camera_pos: Vec3::new(0.0, 0.0, 0.0),
+                compute_normals: true,
+                lod_limits: Vec::new(),
+                lod_factor: 2,
+                lod_camera_altitude: None,
+                lod_morph_band: 0,
+                lod_global_morph_frames: 0,
+                update_vertex: None,
+                lod_global: 1,
+                global_morph: None,
+                center,
                 delta_sub_x,
                 delta_sub_z,
-            }
-        }
-        // create a terrain mesh
-        pub fn create_cpu_mesh(coords: &Vec<Vec<Vec3>>, map_uvs: &Vec<Vec2>, size: usize) -> (CpuMesh, Vec<Vec<Vec3>>, Vec<Vec2>) {
-            let ht = (size as f32 * 0.5) as usize;
-            let hm = (coords.len() as f32 * 0.5) as usize;
-            let start_index = hm - ht;
-            let nb_vertices = size + 1;
-            let mut paths = Vec::new();
-            let mut uvs = Vec::new();
-            let l = map_uvs.len();
-            for i in 0..nb_vertices{
-                let mut path = Vec::new();
-                for j in 0..nb_vertices {
-                    let v3 = coords[start_index + i][start_index + j].clone();
-                    path.push(v3);
-                    if l > 0 {
-                        let uv = map_uvs[(start_index + j) * nb_vertices + start_index + i].clone();
-                        uvs.push(uv);
-                    }
-                }
-                paths.push(path);
-            }
-            let ribbon = create_ribbon(&paths, &uvs);
-            (ribbon.into(), paths, uvs)
+                scrolled_x: 0,
+                scrolled_z: 0,
+            };
+            sampler.sample();
+            Ok(sampler)
         }
 
         // https://github.com/BabylonJS/Extensions/blob/master/DynamicTerrain/src/babylon.dynamicTerrain.ts#L470
-        pub fn update(&mut self, ) {
-            let delta_x= self.position.x - self.camera_pos.x; 
-            let delta_z= self.position.z - self.camera_pos.z;
-            let threshold = self.map.average_sub_size * self.sub_tolerance as f32;  // threshold to trigger the terrain update in every direction x or z
+        // scrolls the map window under the terrain when the camera has moved far enough, returns true if the vertices were sampled again
+        pub fn update(&mut self) -> bool {
+            let spacing = self.source.spacing();
+            let threshold_x = spacing.x * self.sub_tolerance as f32;  // threshold to trigger the terrain update in the x direction
+            let threshold_z = spacing.y * self.sub_tolerance as f32;  // threshold to trigger the terrain update in the z direction
             let mut needs_update = false;
-            if delta_x.abs() > threshold {
-                let map_flgt_nb_x: i32 = (delta_x / threshold) as i32;    // number (+/-) of map cells on the x axis flought over by the camera in the delta shift
-                self.position.x  += threshold * map_flgt_nb_x as f32;
-                self.delta_sub_x += map_flgt_nb_x * self.sub_tolerance;
+            let mut lod_changed = false;
+            if let Some(lod_camera_altitude) = &self.lod_camera_altitude {
+                let lod_global = lod_camera_altitude(self.camera_pos.y).max(1);
+                if lod_global != self.lod_global {
+                    // the current heights are kept to blend them into the ones of the new stride
+                    if self.lod_global_morph_frames > 0 {
+                        let (offsets_x, offsets_z) = self.lod_offsets();
+                        self.global_morph = Some(GlobalMorph {
+                            cells_x: offsets_x.iter().map(|offset| self.scrolled_x + offset).collect(),
+                            cells_z: offsets_z.iter().map(|offset| self.scrolled_z + offset).collect(),
+                            heights: self.paths.iter().map(|path| path.iter().map(|v| v.y).collect()).collect(),
+                            frame: 0,
+                        });
+                    }
+                    self.lod_global = lod_global;
+                    lod_changed = true;
+                    needs_update = true;
+                }
+            }
+            if let Some(morph) = self.global_morph.as_mut().filter(|_| !lod_changed) {
+                morph.frame += 1;
+                if morph.frame >= self.lod_global_morph_frames {
+                    self.global_morph = None;
+                }
                 needs_update = true;
-            } 
-            if delta_z.abs() > threshold {
-                let map_flgt_nb_z = (delta_z / threshold) as i32;    // number (+/-) of map cells on the z axis flought over by the camera in the delta shift
-                self.position.z  += threshold * map_flgt_nb_z as f32;
-                self.delta_sub_z += map_flgt_nb_z * self.sub_tolerance;
+            }
+            // the terrain scrolls on a lattice of thresholds fixed to its initial center, to the step just before the camera:
+            // it's updated once per threshold flought over, and the deltas only depend on the camera position whatever the moves that led there
+            let scrolled_x = ((self.camera_pos.x - self.center.x) / threshold_x).floor() as i32 * self.sub_tolerance;
+            if scrolled_x != self.scrolled_x {
+                self.delta_sub_x += scrolled_x - self.scrolled_x;
+                self.scrolled_x = scrolled_x;
+                self.position.x = self.center.x + self.scrolled_x as f32 * spacing.x;
                 needs_update = true;
-            } 
+            }
+            let scrolled_z = ((self.camera_pos.z - self.center.z) / threshold_z).floor() as i32 * self.sub_tolerance;
+            if scrolled_z != self.scrolled_z {
+                self.delta_sub_z += scrolled_z - self.scrolled_z;
+                self.scrolled_z = scrolled_z;
+                self.position.z = self.center.z + self.scrolled_z as f32 * spacing.y;
+                needs_update = true;
+            }
 
             if needs_update {
-                self.delta_sub_x = Self::modulo(self.delta_sub_x, self.map.subdivisions as i32);
-                self.delta_sub_z = Self::modulo(self.delta_sub_z, self.map.subdivisions as i32);
-                self.position.x = self.camera_pos.x;
-                self.position.z = self.camera_pos.z;
-                self.update_mesh();
+                // the deltas stay within a repeated source, they grow without bounds on the other ones
+                if self.source.dimensions().is_some() && self.source.wrap_mode() == WrapMode::Repeat {
+                    let (delta_sub_z, delta_sub_x) = self.source.wrap_cell(self.delta_sub_z, self.delta_sub_x);
+                    self.delta_sub_x = delta_sub_x;
+                    self.delta_sub_z = delta_sub_z;
+                }
+                self.sample();
             }
+            needs_update
+        }
 
+        // converts world coordinates, where camera_pos and position are expressed, to map coordinates
+        // the terrain center vertex shows the map point (delta_sub_x, delta_sub_z) at the world position
+        pub fn world_to_map(&self, x: f32, z: f32) -> (f32, f32) {
+            let origin = self.source.cell_origin();
+            let spacing = self.source.spacing();
+            let map_x = origin.x + self.delta_sub_x as f32 * spacing.x + x - self.position.x;
+            let map_z = origin.y + self.delta_sub_z as f32 * spacing.y + z - self.position.z;
+            (map_x, map_z)
         }
 
-        fn modulo(a: i32, b: i32) -> i32 {
-            ((a % b)  + b) % b
+        // converts map coordinates back to world coordinates, inverse of world_to_map()
+        pub fn map_to_world(&self, map_x: f32, map_z: f32) -> (f32, f32) {
+            let origin = self.source.cell_origin();
+            let spacing = self.source.spacing();
+            let x = map_x - origin.x - self.delta_sub_x as f32 * spacing.x + self.position.x;
+            let z = map_z - origin.y - self.delta_sub_z as f32 * spacing.y + self.position.z;
+            (x, z)
         }
 
-        pub fn update_mesh(&mut self) {
+        // returns the terrain altitude at the world coordinates (x, z)
+        pub fn get_height_at(&self, x: f32, z: f32) -> f32 {
+            let (map_x, map_z) = self.world_to_map(x, z);
+            self.source.get_height_at(map_x, map_z)
+        }
+
+        // returns the terrain altitude and upward normal at the world coordinates (x, z)
+        pub fn get_height_and_normal_at(&self, x: f32, z: f32) -> (f32, Vec3) {
+            let (map_x, map_z) = self.world_to_map(x, z);
+            self.source.get_height_and_normal_at(map_x, map_z)
+        }
+
+        // casts a ray from the world position "origin" and returns the first terrain hit within max_dist, which must be finite
+        // the ray is cast in map coordinates, so the result stays valid whatever the terrain scrolling
+        pub fn intersect_ray(&self, origin: Vec3, direction: Vec3, max_dist: f32) -> Option<RayHit> {
+            let (map_x, map_z) = self.world_to_map(origin.x, origin.z);
+            let mut hit = self.source.intersect_ray(vec3(map_x, origin.y, map_z), direction, max_dist)?;
+            let (x, z) = self.map_to_world(hit.point.x, hit.point.z);
+            hit.point.x = x;
+            hit.point.z = z;
+            Some(hit)
+        }
+
+        // blends the heights of the vertices in the LOD transition bands towards the coarser level (geomorphing)
+        fn morph_lod_heights(&mut self, offsets_x: &[i32], offsets_z: &[i32]) {
+            let weights_x = self.lod_morph_weights(offsets_x, self.delta_sub_x);
+            let weights_z = self.lod_morph_weights(offsets_z, self.delta_sub_z);
+            let source = Rc::clone(&self.source);
+            let (delta_sub_x, delta_sub_z) = (self.delta_sub_x, self.delta_sub_z);
+            let height = |offset_z: i32, offset_x: i32| source.height_at_cell(delta_sub_z + offset_z, delta_sub_x + offset_x);
+            // height blended along the x axis
+            let height_x = |offset_z: i32, j: usize| {
+                let fine = height(offset_z, offsets_x[j]);
+                match &weights_x[j] {
+                    Some(mw) => {
+                        let coarse = height(offset_z, mw.a) * (1.0 - mw.w) + height(offset_z, mw.b) * mw.w;
+                        fine + (coarse - fine) * mw.t
+                    }
+                    None => fine,
+                }
+            };
+            for (i, weight_z) in weights_z.iter().enumerate() {
+                for (j, weight_x) in weights_x.iter().enumerate() {
+                    if weight_z.is_none() && weight_x.is_none() {
+                        continue;
+                    }
+                    let fine = height_x(offsets_z[i], j);
+                    self.paths[i][j].y = match weight_z {
+                        Some(mw) => {
+                            let coarse = height_x(mw.a, j) * (1.0 - mw.w) + height_x(mw.b, j) * mw.w;
+                            fine + (coarse - fine) * mw.t
+                        }
+                        None => fine,
+                    };
+                }
+            }
+        }
+
+        // returns the map sampling stride of the quads at the given distance in quads from the terrain center
+        fn lod_stride(&self, distance: usize) -> i32 {
+            let level = self.lod_limits.iter().filter(|limit| **limit <= distance).count();
+            (self.lod_global * self.lod_factor.max(1).pow(level as u32)) as i32
+        }
+
+        // returns the map offsets of the vertices on the x axis and on the z axis, relative to the center vertex
+        // the quads further than each LOD limit from the center sample the map with a stride multiplied by lod_factor
+        // all the strides are multiplied by the global stride given by lod_camera_altitude
+        pub fn lod_offsets(&self) -> (Vec<i32>, Vec<i32>) {
+            (self.lod_axis_offsets(self.delta_sub_x), self.lod_axis_offsets(self.delta_sub_z))
+        }
+
+        // returns the map offsets of the vertices on a terrain axis whose center vertex is on the map cell "delta"
+        // the vertices sampled with a stride are snapped to the multiples of the stride in absolute map cells, so that
+        // they stay on the same map points while the terrain scrolls: the first quad of a ring is shorter when needed
+        fn lod_axis_offsets(&self, delta: i32) -> Vec<i32> {
+            let nb_vertices = self.size + 1;
+            let ht = self.size / 2;
+            let mut cells = vec![0; nb_vertices];
+            let center_stride = self.lod_stride(0);
+            cells[ht] = delta - delta.rem_euclid(center_stride);
+            for k in ht..self.size {
+                let stride = self.lod_stride(k - ht);
+                cells[k + 1] = cells[k] + stride - cells[k].rem_euclid(stride);
+            }
+            for k in (0..ht).rev() {
+                let stride = self.lod_stride(ht - 1 - k);
+                let rest = cells[k + 1].rem_euclid(stride);
+                cells[k] = cells[k + 1] - if rest == 0 { stride } else { rest };
+            }
+            cells.iter().map(|cell| cell - delta).collect()
+        }
+
+        // returns, for each vertex on a terrain axis whose center vertex is on the map cell "delta", how to blend its height
+        // towards the next coarser LOD level
+        // the vertices within lod_morph_band quads before a LOD limit are interpolated between the coarse lattice points,
+        // the multiples of the coarse stride in absolute map cells, so that the heights match the coarser ring when reaching the limit
+        fn lod_morph_weights(&self, offsets: &[i32], delta: i32) -> Vec<Option<MorphWeight>> {
+            let ht = self.size / 2;
+            let band = self.lod_morph_band;
+            let mut weights = vec![None; offsets.len()];
+            if band == 0 {
+                return weights;
+            }
+            for (k, weight) in weights.iter_mut().enumerate() {
+                let (distance, side) = if k >= ht { (k - ht, 1) } else { (ht - k, -1) };
+                let max_distance = if side > 0 { self.size - ht } else { ht };
+                let limit = self.lod_limits.iter().filter(|limit| **limit >= distance && **limit <= max_distance).min();
+                if let Some(&limit) = limit {
+                    if limit - distance >= band {
+                        continue;
+                    }
+                    let t = 1.0 - (limit - distance) as f32 / band as f32;
+                    let coarse_stride = self.lod_stride(limit);         // stride of the quads from the limit
+                    let cell = delta + offsets[k];
+                    let a = cell - cell.rem_euclid(coarse_stride) - delta;
+                    let w = (offsets[k] - a) as f32 / coarse_stride as f32;
+                    *weight = Some(MorphWeight { t, a, b: a + coarse_stride, w });
+                }
+            }
+            weights
+        }
+
+        // blends the heights sampled before the last change of the global stride into the current ones,
+        // the previous heights being interpolated at the cells of the current vertices
+        fn morph_global_heights(&mut self, offsets_x: &[i32], offsets_z: &[i32]) {
+            let Some(morph) = &self.global_morph else {
+                return;
+            };
+            let t = morph.frame as f32 / self.lod_global_morph_frames.max(1) as f32;
+            // index of the previous vertex before the cell, and position of the cell between it and the next one
+            // None beyond the previous extent: these vertices weren't shown, so they keep the source heights
+            let locate = |cells: &[i32], cell: i32| {
+                if cell < cells[0] || cell > cells[cells.len() - 1] {
+                    return None;
+                }
+                let k = cells.partition_point(|c| *c <= cell).clamp(1, cells.len() - 1) - 1;
+                let w = (cell - cells[k]) as f32 / (cells[k + 1] - cells[k]) as f32;
+                Some((k, w))
+            };
+            for (i, offset_z) in offsets_z.iter().enumerate() {
+                let Some((k, v)) = locate(&morph.cells_z, self.scrolled_z + offset_z) else {
+                    continue;
+                };
+                for (j, offset_x) in offsets_x.iter().enumerate() {
+                    let Some((l, u)) = locate(&morph.cells_x, self.scrolled_x + offset_x) else {
+                        continue;
+                    };
+                    let h = &morph.heights;
+                    let previous = (h[k][l] * (1.0 - u) + h[k][l + 1] * u) * (1.0 - v) + (h[k + 1][l] * (1.0 - u) + h[k + 1][l + 1] * u) * v;
+                    let y = &mut self.paths[i][j].y;
+                    *y = previous + (*y - previous) * t;
+                }
+            }
+        }
+
+        // samples the vertices from the source around the terrain center, then applies the LOD geomorphing
+        // and the custom vertex function
+        pub fn sample(&mut self) {
+            let (offsets_x, offsets_z) = self.lod_offsets();
+            self.sample_map(&offsets_x, &offsets_z);
+            if self.lod_morph_band > 0 {
+                self.morph_lod_heights(&offsets_x, &offsets_z);
+            }
+            if self.global_morph.is_some() {
+                self.morph_global_heights(&offsets_x, &offsets_z);
+            }
+            let moved = self.update_vertex.is_some() && self.apply_update_vertex(&offsets_x, &offsets_z);
+            // the source normals don't fit the vertices moved by the custom vertex function
+            if moved || (self.compute_normals && !self.source.has_normals()) {
+                self.normals = compute_ribbon_normals(&self.paths);
+            }
+        }
+
+        // returns the mesh of the sampled vertices, with their normals when they're copied from the source or computed
+        pub fn to_cpu_mesh(&self) -> Result<CpuMesh, TerrainError> {
+            let mut cpu_mesh = create_ribbon(&self.paths, &self.uvs)?;
+            if !self.normals.is_empty() {
+                cpu_mesh.tangents = Some(compute_ribbon_tangents(&self.paths, &self.uvs, &self.normals));
+                cpu_mesh.normals = Some(self.normals.clone());
+            }
+            if !self.colors.is_empty() {
+                cpu_mesh.colors = Some(self.colors.clone());
+            }
+            Ok(cpu_mesh)
+        }
+
+        // copies the positions, uvs, normals and colors of the source cells sampled at the passed x and z offsets around the terrain center
+        fn sample_map(&mut self, offsets_x: &[i32], offsets_z: &[i32]) {
             let nb_vertices = self.size + 1;
-            for i in 0..nb_vertices {
-                let map_i = Self::modulo(self.delta_sub_z + i as i32, self.map.subdivisions as i32);
-                for j in 0..nb_vertices {
-                    let map_j = Self::modulo(self.delta_sub_x + j as i32, self.map.subdivisions as i32);
-                    let v3 = self.map.coords[map_i as usize][map_j as usize];
-                    self.paths[i][j].y = v3.y;
-                    self.uvs[i * nb_vertices + j].x = self.map.uvs[map_i as usize * self.map.subdivisions + map_j as usize].x;
-                    self.uvs[i * nb_vertices + j].y = self.map.uvs[map_i as usize * self.map.subdivisions + map_j as usize].y;
+            let has_normals = self.source.has_normals();
+            if has_normals {
+                self.normals.resize(nb_vertices * nb_vertices, vec3(0.0, 1.0, 0.0));
+            } else {
+                self.normals.clear();
+            }
+            // the colors are cleared too when the source has none, the custom vertex function sets them again
+            let has_colors = self.source.has_colors();
+            if has_colors {
+                self.colors.resize(nb_vertices * nb_vertices, Srgba::WHITE);
+            } else {
+                self.colors.clear();
+            }
+            let spacing = self.source.spacing();
+            self.length = vec2((offsets_x[nb_vertices - 1] - offsets_x[0]) as f32 * spacing.x, (offsets_z[nb_vertices - 1] - offsets_z[0]) as f32 * spacing.y);
+            for (i, offset_z) in offsets_z.iter().enumerate() {
+                let map_i = self.delta_sub_z + offset_z;
+                let z = self.center.z + *offset_z as f32 * spacing.y;
+                for (j, offset_x) in offsets_x.iter().enumerate() {
+                    let map_j = self.delta_sub_x + offset_x;
+                    self.paths[i][j].x = self.center.x + *offset_x as f32 * spacing.x;
+                    self.paths[i][j].y = self.source.height_at_cell(map_i, map_j);
+                    self.paths[i][j].z = z;
+                    self.uvs[i * nb_vertices + j] = self.source.uv_at_cell(map_i, map_j);
+                    if has_normals {
+                        self.normals[i * nb_vertices + j] = self.source.normal_at_cell(map_i, map_j);
+                    }
+                    if has_colors {
+                        self.colors[i * nb_vertices + j] = self.source.color_at_cell(map_i, map_j);
+                    }
+                }
+            }
+        }
+
+        // calls the custom vertex function on each vertex, returns true if it moved any vertex
+        // the colors start from the source colors, or are reset to white if the source has none
+        fn apply_update_vertex(&mut self, offsets_x: &[i32], offsets_z: &[i32]) -> bool {
+            let nb_vertices = self.size + 1;
+            let mut moved = false;
+            if !self.source.has_colors() {
+                self.colors.resize(nb_vertices * nb_vertices, Srgba::WHITE);
+            }
+            if let Some(update_vertex) = self.update_vertex.as_mut() {
+                for (i, offset_z) in offsets_z.iter().enumerate() {
+                    for (j, offset_x) in offsets_x.iter().enumerate() {
+                        let (map_i, map_j) = self.source.wrap_cell(self.delta_sub_z + offset_z, self.delta_sub_x + offset_x);
+                        let index = i * nb_vertices + j;
+                        let mut vertex = VertexData {
+                            position: self.paths[i][j],
+                            uv: self.uvs[index],
+                            color: self.colors[index],
+                        };
+                        update_vertex(&mut vertex, map_i, map_j);
+                        moved |= vertex.position != self.paths[i][j];
+                        self.paths[i][j] = vertex.position;
+                        self.uvs[index] = vertex.uv;
+                        self.colors[index] = vertex.color;
+                    }
                 }
             }
-            morph_ribbon(&mut self.mesh.geometry, &mut &self.paths, &self.uvs);
+            moved
         }
     }
 
+    pub struct Terrain {
+        pub sampler: TerrainSampler,    // terrain vertices, scrolled and sampled from the source around the camera
+        pub cpu_mesh: CpuMesh,      // mesh the terrain was created with, not updated: to_cpu_mesh() returns the current one
+        pub cpu_material: CpuMaterial,
+        pub mesh: Gm<Mesh, PhysicalMaterial>,
+        context: Context,
+    }
+    impl Terrain {
+        pub fn new(context: &Context, source: Rc<dyn HeightSource>, size: usize, cpu_material: CpuMaterial) -> Result<Self, TerrainError> {
+            // the initial vertices are sampled from the source before the mesh is built, by the same routine as the updates
+            let sampler = TerrainSampler::new(source, size)?;
+            let cpu_mesh = sampler.to_cpu_mesh()?;
+            let material = PhysicalMaterial::new_transparent(context, &cpu_material);
+            let mesh = Gm::new(Mesh::new(context, &cpu_mesh), material);
+            Ok(Terrain {
+                sampler,
+                cpu_mesh,
+                cpu_material,
+                mesh,
+                context: context.clone(),
+            })
+        }
+
+        // scrolls the terrain with the camera, the mesh buffers are updated when the vertices were sampled again
+        pub fn update(&mut self) {
+            if self.sampler.update() {
+                self.update_buffers();
+            }
+        }
+
+        // samples the vertices again and updates the mesh buffers, after a change of the terrain settings
+        pub fn update_mesh(&mut self) {
+            self.sampler.sample();
+            self.update_buffers();
+        }
+
+        // returns the mesh of the current terrain vertices, with their uvs, normals and colors, for instance to export it
+        pub fn to_cpu_mesh(&self) -> Result<CpuMesh, TerrainError> {
+            self.sampler.to_cpu_mesh()
+        }
+
+        // returns the terrain altitude at the world coordinates (x, z)
+        pub fn get_height_at(&self, x: f32, z: f32) -> f32 {
+            self.sampler.get_height_at(x, z)
+        }
+
+        // returns the terrain altitude and upward normal at the world coordinates (x, z)
+        pub fn get_height_and_normal_at(&self, x: f32, z: f32) -> (f32, Vec3) {
+            self.sampler.get_height_and_normal_at(x, z)
+        }
+
+        // casts a ray from the world position "origin" and returns the first terrain hit within max_dist, which must be finite
+        pub fn intersect_ray(&self, origin: Vec3, direction: Vec3, max_dist: f32) -> Option<RayHit> {
+            self.sampler.intersect_ray(origin, direction, max_dist)
+        }
+
+        // copies the sampled vertices into the mesh buffers
+        fn update_buffers(&mut self) {
+            let sampler = &self.sampler;
+            morph_ribbon(&mut self.mesh.geometry, &sampler.paths, &sampler.uvs, &sampler.normals, false);
+            if sampler.colors.is_empty() {
+                *self.mesh.geometry.colors_mut() = None;
+            } else {
+                let colors: Vec<_> = sampler.colors.iter().map(|c| c.to_linear_srgb()).collect();
+                match self.mesh.geometry.colors_mut() {
+                    Some(vb_colors) => vb_colors.fill(&colors),
+                    None => *self.mesh.geometry.colors_mut() = Some(VertexBuffer::new_with_data(&self.context, &colors)),
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::test_utils::test_utils::temp_path;
+
+        // map of width x depth points with uneven heights, the points are 2 apart on x and 3 apart on z
+        fn test_map(width: usize, depth: usize) -> Map {
+            let data = (0..width * depth).map(|k| ((k % width * 7 + k / width * 13) % 17) as f32 * 0.25).collect();
+            Map::new(MapOptions {
+                source: MapSource::Heights { width, depth, data },
+                spacing: vec2(2.0, 3.0),
+                altitude_factor: 1.0,
+                origin: vec3(10.0, 0.0, -20.0),
+                ..Default::default()
+            }).unwrap()
+        }
+
+        fn assert_close(a: &[Vec3], b: &[Vec3]) {
+            assert_eq!(a.len(), b.len());
+            for (a, b) in a.iter().zip(b) {
+                assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+            }
+        }
+
+        fn assert_meshes_close(mesh: &CpuMesh, expected: &CpuMesh) {
+            assert_close(&mesh.positions.to_f32(), &expected.positions.to_f32());
+            assert_close(mesh.normals.as_ref().unwrap(), expected.normals.as_ref().unwrap());
+            assert_eq!(mesh.uvs, expected.uvs);
+            assert_eq!(mesh.indices.to_u32(), expected.indices.to_u32());
+        }
+
+        #[test]
+        fn initial_vertices_match_the_map_ribbon() {
+            let map = Rc::new(test_map(16, 12));
+            let sampler = TerrainSampler::new(map.clone(), 6).unwrap();
+            // the terrain center vertex is on the map center point
+            let (hm_x, hm_z, ht) = (8, 6, 3);
+            let paths: Vec<Vec<Vec3>> = (0..7).map(|i| (0..7).map(|j| map.point(hm_z - ht + i, hm_x - ht + j)).collect()).collect();
+            let uvs: Vec<Vec2> = (0..7).flat_map(|i| (0..7).map(move |j| (i, j))).map(|(i, j)| map.uv(hm_z - ht + i, hm_x - ht + j)).collect();
+            let expected = create_ribbon(&paths, &uvs).unwrap();
+            assert_meshes_close(&sampler.to_cpu_mesh().unwrap(), &expected);
+        }
+
+        #[test]
+        fn initial_mesh_has_the_source_normals_and_valid_tangents() {
+            let mut map = test_map(16, 12);
+            map.compute_normals();
+            let map = Rc::new(map);
+            let sampler = TerrainSampler::new(map.clone(), 6).unwrap();
+            let mesh = sampler.to_cpu_mesh().unwrap();
+            let normals = mesh.normals.as_ref().unwrap();
+            assert_eq!(normals[0], map.normals[(6 - 3) * 16 + 8 - 3]);
+            let tangents = mesh.tangents.as_ref().unwrap();
+            assert_eq!(tangents.len(), 49);
+            assert!(tangents.iter().all(|t| t.x.is_finite() && t.y.is_finite() && t.z.is_finite()));
+        }
+
+        #[test]
+        fn map_ribbon_and_source_normals_point_up() {
+            let mut map = test_map(8, 6);
+            map.compute_normals();
+            let paths: Vec<Vec<Vec3>> = (0..6).map(|i| (0..8).map(|j| map.point(i, j)).collect()).collect();
+            let ribbon_normals = create_ribbon(&paths, &[]).unwrap().normals.unwrap();
+            for i in 1..5 {
+                for j in 1..7 {
+                    let normal = map.normals[i * 8 + j];
+                    assert!(normal.y > 0.0, "({}, {}): {:?}", i, j, normal);
+                    assert!((normal - ribbon_normals[i * 8 + j]).magnitude() < 1e-5);
+                    let p = map.point(i, j);
+                    let (_, queried) = map.get_height_and_normal_at(p.x + 0.5, p.z + 0.5);
+                    assert!(queried.y > 0.0 && queried.dot(normal) > 0.0);
+                }
+            }
+        }
+
+        #[test]
+        fn scrolled_back_vertices_match_the_initial_mesh() {
+            let map = Rc::new(test_map(16, 12));
+            let mut sampler = TerrainSampler::new(map, 6).unwrap();
+            sampler.camera_pos = sampler.position;
+            let initial = sampler.to_cpu_mesh().unwrap();
+            sampler.camera_pos.x += 7.5;
+            sampler.camera_pos.z -= 7.0;
+            assert!(sampler.update());
+            assert_ne!(sampler.to_cpu_mesh().unwrap().uvs, initial.uvs);
+            sampler.camera_pos.x -= 7.5;
+            sampler.camera_pos.z += 7.0;
+            assert!(sampler.update());
+            assert_meshes_close(&sampler.to_cpu_mesh().unwrap(), &initial);
+        }
+
+        #[test]
+        fn world_point_keeps_its_height_while_scrolling() {
+            let map = Rc::new(test_map(16, 12));
+            let mut sampler = TerrainSampler::new(map, 6).unwrap();
+            sampler.camera_pos = sampler.position;
+            let (x, z) = (sampler.position.x + 3.3, sampler.position.z - 4.1);
+            let height = sampler.get_height_at(x, z);
+            // the last moves scroll across the map edges
+            for (dx, dz) in [(2.7, 0.0), (4.4, -3.5), (-9.1, 6.2), (30.0, 1.0), (0.4, -40.0)] {
+                sampler.camera_pos.x += dx;
+                sampler.camera_pos.z += dz;
+                sampler.update();
+                assert!((sampler.get_height_at(x, z) - height).abs() < 1e-3);
+            }
+        }
+
+        #[test]
+        fn terrain_scrolls_once_per_threshold_flown_over() {
+            let map = Rc::new(test_map(32, 32));
+            let mut sampler = TerrainSampler::new(map, 8).unwrap();
+            sampler.sub_tolerance = 2;
+            sampler.camera_pos = sampler.position;
+            let start = sampler.position;
+            // the threshold is 2 cells of 2 units
+            sampler.camera_pos.x += 3.0;
+            assert!(!sampler.update());
+            sampler.camera_pos.x += 1.5;
+            assert!(sampler.update());
+            assert_eq!(sampler.position.x, start.x + 4.0);
+            // moving back and forth within the step doesn't sample again
+            for dx in [-0.4, 0.3, 2.0, -1.5] {
+                sampler.camera_pos.x += dx;
+                assert!(!sampler.update());
+            }
+            assert_eq!(sampler.position.x, start.x + 4.0);
+        }
+
+        #[test]
+        fn camera_moves_ending_at_the_same_position_give_the_same_deltas() {
+            let source: Rc<dyn HeightSource> = Rc::new(crate::noise::noise::NoiseSource::new(&[NoiseSettings::default()], vec2(2.0, 3.0), 50.0));
+            let mut direct = TerrainSampler::new(Rc::clone(&source), 8).unwrap();
+            let mut steps = TerrainSampler::new(source, 8).unwrap();
+            // small steps, then back and forth around a threshold
+            let moves = std::iter::repeat_n((1.3, -0.9), 20).chain([(2.6, 0.0), (-0.7, 0.0), (0.0, -4.4), (0.0, 2.9)]);
+            let (mut dx, mut dz) = (0.0, 0.0);
+            for (x, z) in moves {
+                steps.camera_pos.x += x;
+                steps.camera_pos.z += z;
+                steps.update();
+                dx += x;
+                dz += z;
+            }
+            direct.camera_pos.x += dx;
+            direct.camera_pos.z += dz;
+            direct.update();
+            assert_eq!((steps.delta_sub_x, steps.delta_sub_z), (direct.delta_sub_x, direct.delta_sub_z));
+            assert_eq!(steps.position, direct.position);
+            assert_eq!(steps.paths, direct.paths);
+        }
+
+        #[test]
+        fn lod_vertices_stay_on_the_stride_multiples_while_scrolling() {
+            let map = Rc::new(test_map(64, 64));
+            let mut sampler = TerrainSampler::new(map, 16).unwrap();
+            sampler.lod_limits = vec![3, 6];
+            sampler.camera_pos = sampler.position;
+            for _ in 0..5 {
+                let (offsets_x, offsets_z) = sampler.lod_offsets();
+                for (offsets, delta) in [(&offsets_x, sampler.delta_sub_x), (&offsets_z, sampler.delta_sub_z)] {
+                    assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+                    for (k, offset) in offsets.iter().enumerate() {
+                        // each vertex is on a multiple of the stride of the quad before it, seen from the center
+                        let distance = (k as i32 - 8).unsigned_abs() as usize;
+                        let stride = sampler.lod_stride(distance.saturating_sub(1));
+                        assert_eq!((delta + offset).rem_euclid(stride), 0);
+                    }
+                }
+                // one cell on each axis
+                sampler.camera_pos.x += 2.0;
+                sampler.camera_pos.z += 3.0;
+                assert!(sampler.update());
+            }
+        }
+
+        #[test]
+        fn global_stride_change_blends_the_heights_over_several_updates() {
+            let map = Rc::new(test_map(64, 64));
+            let new_sampler = |altitude: f32| {
+                let mut sampler = TerrainSampler::new(map.clone(), 16).unwrap();
+                sampler.lod_camera_altitude = Some(Box::new(|altitude| if altitude > 100.0 { 2 } else { 1 }));
+                sampler.camera_pos = sampler.position;
+                sampler.camera_pos.y = altitude;
+                sampler.update();
+                sampler
+            };
+            let fine = new_sampler(0.0);
+            let coarse = new_sampler(200.0);
+            let mut sampler = new_sampler(0.0);
+            sampler.lod_global_morph_frames = 4;
+            // growing stride: the coarse vertices are on fine ones, and the outer ones beyond the previous extent
+            // weren't shown, so they all start from the coarse heights
+            sampler.camera_pos.y = 200.0;
+            assert!(sampler.update());
+            let (offsets_x, _) = sampler.lod_offsets();
+            assert!(offsets_x[0] < -8 && offsets_x[offsets_x.len() - 1] > 8);
+            assert_eq!(sampler.paths, coarse.paths);
+            for _ in 0..4 {
+                assert!(sampler.update());
+            }
+            assert!(!sampler.update());
+            // shrinking stride: the fine vertices between the coarse ones start from the coarse surface
+            sampler.camera_pos.y = 0.0;
+            assert!(sampler.update());
+            assert_eq!(sampler.paths[8][8].y, fine.paths[8][8].y);
+            let between = (fine.paths[8][8].y + fine.paths[8][10].y) * 0.5;
+            assert_ne!(between, fine.paths[8][9].y);
+            assert!((sampler.paths[8][9].y - between).abs() < 1e-5);
+            // then reach the fine heights once the frames are elapsed
+            for _ in 0..3 {
+                assert!(sampler.update());
+                assert_ne!(sampler.paths, fine.paths);
+            }
+            assert!(sampler.update());
+            assert_eq!(sampler.paths, fine.paths);
+            assert!(!sampler.update());
+        }
+
+        #[test]
+        fn custom_vertex_function_recomputes_the_normals_and_its_removal_clears_the_colors() {
+            let mut map = test_map(16, 12);
+            map.compute_normals();
+            let map = Rc::new(map);
+            let mut sampler = TerrainSampler::new(map, 6).unwrap();
+            let source_normals = sampler.normals.clone();
+            sampler.update_vertex = Some(Box::new(|vertex: &mut VertexData, _, map_j| {
+                vertex.position.y += map_j as f32;
+                vertex.color = Srgba::RED;
+            }));
+            sampler.sample();
+            assert_eq!(sampler.normals, compute_ribbon_normals(&sampler.paths));
+            assert_ne!(sampler.normals, source_normals);
+            assert!(sampler.colors.iter().all(|c| *c == Srgba::RED));
+            sampler.update_vertex = None;
+            sampler.sample();
+            assert_eq!(sampler.normals, source_normals);
+            assert!(sampler.colors.is_empty());
+        }
+
+        #[test]
+        fn size_mismatches_are_reported_with_the_expected_size() {
+            let result = Map::new(MapOptions { source: MapSource::Heights { width: 4, depth: 3, data: vec![0.0; 10] }, ..Default::default() });
+            assert!(matches!(result, Err(TerrainError::SizeMismatch { expected: 12, found: 10 })));
+            let mut map = test_map(16, 12);
+            assert!(matches!(map.set_colors(vec![Srgba::WHITE; 5]), Err(TerrainError::SizeMismatch { expected: 192, found: 5 })));
+            assert!(matches!(map.create_region_mesh(2, 10, 4, 8), Err(TerrainError::SizeMismatch { expected: 6, found: 8 })));
+            assert!(map.create_region_mesh(2, 10, 4, 6).is_ok());
+        }
+
+        #[test]
+        fn raw_elevations_are_moved_into_the_map() {
+            let data = vec![1.0; 12];
+            let buffer = data.as_ptr();
+            let map = Map::new(MapOptions { source: MapSource::Heights { width: 4, depth: 3, data }, ..Default::default() }).unwrap();
+            assert_eq!(map.heights.as_ptr(), buffer);
+        }
+
+        #[test]
+        fn raw_heightfields_round_trip() {
+            let data: Vec<f32> = (0..12).map(|k| k as f32 / 11.0).collect();
+            let map = Map::new(MapOptions {
+                source: MapSource::Heights { width: 4, depth: 3, data: data.clone() },
+                spacing: vec2(2.0, 3.0),
+                altitude_factor: 100.0,
+                ..Default::default()
+            }).unwrap();
+            let path = temp_path("map.raw");
+            let round_trip = |format: fn() -> RawFormat, tolerance: f32| {
+                map.write_raw(&path, format()).unwrap();
+                let loaded = Map::from_raw(&path, 4, 3, format(), vec2(2.0, 3.0), 100.0).unwrap();
+                assert_eq!(loaded.spacing, map.spacing);
+                for (a, b) in loaded.heights.iter().zip(&map.heights) {
+                    assert!((a - b).abs() <= tolerance, "{} != {}", a, b);
+                }
+            };
+            round_trip(|| RawFormat::R16, 100.0 / 65535.0);
+            round_trip(|| RawFormat::R32, 1e-4);
+            // 8-bit samples are normalized then scaled
+            std::fs::write(&path, [0u8, 51, 102, 255]).unwrap();
+            let r8 = Map::from_raw(&path, 2, 2, RawFormat::R8, vec2(1.0, 1.0), 10.0);
+            // a file of the wrong length for the given dimensions
+            std::fs::write(&path, [0u8; 10]).unwrap();
+            let truncated = Map::from_raw(&path, 4, 3, RawFormat::R16, vec2(1.0, 1.0), 1.0);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(r8.unwrap().heights, vec![0.0, 2.0, 4.0, 10.0]);
+            assert!(matches!(truncated, Err(TerrainError::SizeMismatch { expected: 24, found: 10 })));
+        }
+
+        #[test]
+        fn cache_round_trip_keeps_the_map() {
+            let mut map = Map::new(MapOptions {
+                source: MapSource::Heights { width: 3, depth: 2, data: vec![0.0, 0.5, 1.0, 0.25, 0.75, 0.5] },
+                spacing: vec2(2.0, 3.0),
+                altitude_factor: 10.0,
+                origin: vec3(1.0, -5.0, 2.0),
+                compute_normals: true,
+                wrap: WrapMode::Clamp,
+                ..Default::default()
+            }).unwrap();
+            map.compute_colors(|p| Srgba::new((p.y + 10.0) as u8, 0, 0, 255));
+            let path = temp_path("map.dtmc");
+            map.save_cache(&path).unwrap();
+            let loaded = Map::load_cache(&path);
+            std::fs::remove_file(&path).unwrap();
+            let loaded = loaded.unwrap();
+            assert_eq!((loaded.width, loaded.depth), (3, 2));
+            assert_eq!(loaded.heights, map.heights);
+            assert_eq!(loaded.normals, map.normals);
+            assert_eq!(loaded.colors, map.colors);
+            assert_eq!(loaded.spacing, map.spacing);
+            assert_eq!(loaded.transform, map.transform);
+            assert_eq!(loaded.origin, map.origin);
+            assert!(loaded.wrap == WrapMode::Clamp);
+            assert_eq!(loaded.height_at_cell(-1, 5), map.height(0, 2));
+        }
+
+        #[test]
+        fn cache_of_another_version_is_rejected() {
+            let path = temp_path("old.dtmc");
+            let mut bytes = CACHE_MAGIC.to_vec();
+            bytes.extend_from_slice(&1u32.to_le_bytes());
+            bytes.extend_from_slice(&[0; 16]);
+            std::fs::write(&path, bytes).unwrap();
+            let result = Map::load_cache(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert!(matches!(result, Err(TerrainError::CacheVersion { expected: CACHE_VERSION, found: 1 })));
+        }
+
+        #[test]
+        fn heights_are_written_back_with_the_inverse_transform() {
+            let values: Vec<u16> = vec![0, 12345, 65535, 40000, 777, 30000];
+            let img = DynamicImage::ImageLuma16(ImageBuffer::from_fn(3, 2, |i, j| Luma([values[(j * 3 + i) as usize]])));
+            let options = |source, curve| MapOptions {
+                source,
+                altitude_range: Some((-100.0, 300.0)),
+                sea_level: 20.0,
+                origin: vec3(0.0, 7.0, 0.0),
+                curve,
+                ..Default::default()
+            };
+            let map = Map::new(options(MapSource::Image(img.clone()), HeightCurve::Linear)).unwrap();
+            assert_eq!(map.transform, HeightTransform { scale: 400.0, offset: -113.0, curved: false });
+            assert!((map.height(0, 2) - 287.0).abs() < 1e-3);
+
+            let path = temp_path("inverse.r32");
+            map.write_raw(&path, RawFormat::R32).unwrap();
+            let bytes = std::fs::read(&path);
+            std::fs::remove_file(&path).unwrap();
+            let raw: Vec<f32> = bytes.unwrap().chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+            for (value, source) in raw.iter().zip(&values) {
+                assert!((value - *source as f32 / 65535.0).abs() < 1e-5, "{} != {}", value, source);
+            }
+
+            let path = temp_path("inverse.png");
+            map.write_heightmap(&path, BitDepth::Sixteen, HeightNormalization::AltitudeFactor).unwrap();
+            let reloaded = Map::new(options(MapSource::File(path.clone()), HeightCurve::Linear));
+            std::fs::remove_file(&path).unwrap();
+            for (a, b) in reloaded.unwrap().heights.iter().zip(&map.heights) {
+                assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+            }
+        }
+
+        #[test]
+        fn non_invertible_heights_are_not_written() {
+            let img = DynamicImage::ImageLuma8(ImageBuffer::from_fn(2, 2, |i, j| Luma([(i * 100 + j * 50) as u8])));
+            let curved = Map::new(MapOptions { source: MapSource::Image(img), curve: HeightCurve::Gamma(2.0), ..Default::default() }).unwrap();
+            let flat = Map::new(MapOptions {
+                source: MapSource::Heights { width: 2, depth: 2, data: vec![0.0; 4] },
+                altitude_factor: 0.0,
+                ..Default::default()
+            }).unwrap();
+            let path = temp_path("non_invertible.png");
+            for map in [curved, flat] {
+                assert!(matches!(map.write_raw(&path, RawFormat::R16), Err(TerrainError::NonInvertibleHeights(_))));
+                assert!(matches!(map.write_heightmap(&path, BitDepth::Eight, HeightNormalization::AltitudeFactor), Err(TerrainError::NonInvertibleHeights(_))));
+            }
+            assert!(!std::path::Path::new(&path).exists());
+        }
+
+        #[test]
+        fn rgb_encoded_pixels_are_decoded_in_meters() {
+            let pixels = [[1, 134, 160], [128, 0, 0], [128, 100, 128]];
+            let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(3, 2, |i, _| image::Rgb(pixels[i as usize])));
+            let decode = |encoding| Map::new(MapOptions {
+                source: MapSource::Image(img.clone()),
+                encoding,
+                altitude_range: Some((-50.0, 50.0)),
+                curve: HeightCurve::Gamma(2.0),
+                sea_level: 30.0,
+                origin: vec3(0.0, 5.0, 0.0),
+                ..Default::default()
+            }).unwrap();
+            // only the origin is added to the decoded altitudes
+            let mapbox = decode(HeightEncoding::Mapbox);
+            assert!((mapbox.height(1, 0) - 5.0).abs() < 1e-3);
+            let terrarium = decode(HeightEncoding::Terrarium);
+            assert!((terrarium.height(1, 1) - 5.0).abs() < 1e-3);
+            assert!((terrarium.height(1, 2) - 105.5).abs() < 1e-3);
+            assert_eq!(terrarium.transform, HeightTransform { scale: 1.0, offset: 5.0, curved: false });
+        }
+
+        #[test]
+        fn colors_are_loaded_from_an_image_of_the_map_dimensions() {
+            let mut map = test_map(3, 4);
+            let path = temp_path("colors.png");
+            let pixel = |i: u32, j: u32| image::Rgba([i as u8 * 10, j as u8 * 20, 30, 255]);
+            ImageBuffer::from_fn(3, 4, pixel).save(&path).unwrap();
+            let loaded = map.load_colors_from_file(&path);
+            ImageBuffer::from_fn(4, 3, pixel).save(&path).unwrap();
+            let swapped = map.load_colors_from_file(&path);
+            std::fs::remove_file(&path).unwrap();
+            loaded.unwrap();
+            assert_eq!(map.colors.len(), 12);
+            assert_eq!(map.colors[2 * 3 + 1], Srgba::new(10, 40, 30, 255));
+            let err = swapped.unwrap_err();
+            assert!(matches!(err, TerrainError::DimensionMismatch { expected: (3, 4), found: (4, 3) }));
+            assert_eq!(err.to_string(), "dimension mismatch: 3x4 expected, 4x3 found");
+        }
+    }
 }
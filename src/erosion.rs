@@ -0,0 +1,258 @@
+pub mod erosion {
+
+    use three_d::{vec2, InnerSpace, Vec2};
+    use crate::dt::terrain::Map;
+    use crate::source::source::{HeightSource, WrapMode};
+
+    ///
+    /// Parameters of the particle-based hydraulic erosion.
+    /// The heights and rates are expressed in map height units, the distances in map cells.
+    ///
+    pub struct HydraulicErosion {
+        pub droplets: usize,            // number of simulated water droplets
+        pub seed: u64,                  // seed of the droplet start positions, the same seed gives the same result
+        pub max_lifetime: usize,        // maximal number of steps of a droplet
+        pub inertia: f32,               // 0: the droplets follow the slope, 1: they keep their direction
+        pub capacity: f32,              // sediment capacity factor of the droplets
+        pub min_capacity: f32,          // sediment capacity of the droplets on flat ground
+        pub erosion_rate: f32,          // fraction of the free capacity taken from the ground at each step
+        pub deposition_rate: f32,       // fraction of the excess sediment dropped at each step
+        pub evaporation: f32,           // fraction of the water evaporated at each step
+        pub gravity: f32,
+        pub radius: usize,              // radius in cells of the erosion brush
+        pub initial_water: f32,
+        pub initial_speed: f32,
+    }
+
+    impl Default for HydraulicErosion {
+        fn default() -> Self {
+            HydraulicErosion {
+                droplets: 70000,
+                seed: 1,
+                max_lifetime: 30,
+                inertia: 0.05,
+                capacity: 4.0,
+                min_capacity: 0.01,
+                erosion_rate: 0.3,
+                deposition_rate: 0.3,
+                evaporation: 0.01,
+                gravity: 4.0,
+                radius: 3,
+                initial_water: 1.0,
+                initial_speed: 1.0,
+            }
+        }
+    }
+
+    // SplitMix64 generator, so the erosion only depends on its seed, whatever the platform
+    struct Random {
+        state: u64,
+    }
+
+    impl Random {
+        fn next_u64(&mut self) -> u64 {
+            self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = self.state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        }
+
+        // uniform value in 0..1
+        fn next_f32(&mut self) -> f32 {
+            (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+        }
+    }
+
+    // bilinear height and gradient at the position (x, z) in cells, the cells are wrapped like the map
+    fn height_and_gradient(map: &Map, x: f32, z: f32) -> (f32, Vec2) {
+        let (j, i) = (x.floor() as i32, z.floor() as i32);
+        let (u, v) = (x - j as f32, z - i as f32);
+        let h00 = map.height_at_cell(i, j);
+        let h01 = map.height_at_cell(i, j + 1);
+        let h10 = map.height_at_cell(i + 1, j);
+        let h11 = map.height_at_cell(i + 1, j + 1);
+        let gradient = vec2(
+            (h01 - h00) * (1.0 - v) + (h11 - h10) * v,
+            (h10 - h00) * (1.0 - u) + (h11 - h01) * u,
+        );
+        let height = h00 * (1.0 - u) * (1.0 - v) + h01 * u * (1.0 - v) + h10 * (1.0 - u) * v + h11 * u * v;
+        (height, gradient)
+    }
+
+    // drops sediment on the corners of the cell (i, j), weighted by the position (u, v) in the cell
+    fn deposit(map: &mut Map, i: i32, j: i32, u: f32, v: f32, amount: f32) {
+        let corners = [(i, j, (1.0 - u) * (1.0 - v)), (i, j + 1, u * (1.0 - v)), (i + 1, j, (1.0 - u) * v), (i + 1, j + 1, u * v)];
+        for (ci, cj, weight) in corners {
+            if let Some(index) = map.cell_index(ci, cj) {
+                map.heights[index] += amount * weight;
+            }
+        }
+    }
+
+    // whether the position (x, z) in cells is on the map, always true for a repeated map
+    fn is_on_map(map: &Map, x: f32, z: f32) -> bool {
+        match map.wrap {
+            WrapMode::Repeat => true,
+            WrapMode::Clamp => x >= 0.0 && z >= 0.0 && x < (map.width - 1) as f32 && z < (map.depth - 1) as f32,
+        }
+    }
+
+    ///
+    /// Erodes the map heights by simulating water droplets flowing down the slopes, taking and dropping sediment.
+    /// A repeated map stays seamless, the droplets flowing across its edges. On a clamped map, the droplets stop at the edges
+    /// and drop their sediment there. The precomputed normals are updated.
+    ///
+    pub fn hydraulic_erosion(map: &mut Map, settings: &HydraulicErosion) {
+        if map.width < 2 || map.depth < 2 {
+            return;
+        }
+        let mut random = Random { state: settings.seed };
+
+        // erosion brush: cell offsets and weights decreasing with the distance, summing to 1
+        let radius = settings.radius.max(1) as i32;
+        let mut brush = Vec::new();
+        for di in -radius..=radius {
+            for dj in -radius..=radius {
+                let weight = radius as f32 - ((di * di + dj * dj) as f32).sqrt();
+                if weight > 0.0 {
+                    brush.push((di, dj, weight));
+                }
+            }
+        }
+        let weight_sum: f32 = brush.iter().map(|(_, _, weight)| weight).sum();
+
+        // a repeated map has no edge, the droplets start anywhere
+        let (start_width, start_depth) = match map.wrap {
+            WrapMode::Repeat => (map.width as f32, map.depth as f32),
+            WrapMode::Clamp => ((map.width - 1) as f32, (map.depth - 1) as f32),
+        };
+        for _ in 0..settings.droplets {
+            let mut x = random.next_f32() * start_width;
+            let mut z = random.next_f32() * start_depth;
+            let mut direction = vec2(0.0, 0.0);
+            let mut speed = settings.initial_speed;
+            let mut water = settings.initial_water;
+            let mut sediment = 0.0;
+
+            for _ in 0..settings.max_lifetime {
+                let (j, i) = (x.floor() as i32, z.floor() as i32);
+                let (u, v) = (x - j as f32, z - i as f32);
+                let (height, gradient) = height_and_gradient(map, x, z);
+
+                // the direction follows the slope, smoothed by the inertia
+                direction = direction * settings.inertia - gradient * (1.0 - settings.inertia);
+                if direction.magnitude2() == 0.0 {
+                    break;
+                }
+                direction = direction.normalize();
+                x += direction.x;
+                z += direction.y;
+                if !is_on_map(map, x, z) {
+                    // the sediment stays on the map, else its edges would become sinks dug deeper by every droplet
+                    deposit(map, i, j, u, v, sediment);
+                    break;
+                }
+
+                let delta_height = height_and_gradient(map, x, z).0 - height;
+                let capacity = (-delta_height * speed * water * settings.capacity).max(settings.min_capacity);
+                if sediment > capacity || delta_height > 0.0 {
+                    // uphill, the sediment fills the pit, else the excess sediment is dropped on the cell corners
+                    let amount = if delta_height > 0.0 { delta_height.min(sediment) } else { (sediment - capacity) * settings.deposition_rate };
+                    sediment -= amount;
+                    deposit(map, i, j, u, v, amount);
+                } else {
+                    // never digs deeper than the height difference, so that no hole is created
+                    let amount = ((capacity - sediment) * settings.erosion_rate).min(-delta_height);
+                    for (di, dj, weight) in &brush {
+                        if let Some(index) = map.cell_index(i + di, j + dj) {
+                            let removed = amount * weight / weight_sum;
+                            map.heights[index] -= removed;
+                            sediment += removed;
+                        }
+                    }
+                }
+                // the droplets speed up going down, delta_height being negative
+                speed = (speed * speed - delta_height * settings.gravity).max(0.0).sqrt();
+                water *= 1.0 - settings.evaporation;
+            }
+        }
+        if !map.normals.is_empty() {
+            map.compute_normals();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::dt::terrain::{MapOptions, MapSource};
+
+        // 32 x 24 map of hills, seamless when repeated
+        fn hills(wrap: WrapMode) -> Map {
+            let (width, depth) = (32, 24);
+            let tau = std::f32::consts::TAU;
+            let data = (0..width * depth).map(|k| {
+                let (x, z) = ((k % width) as f32 / width as f32, (k / width) as f32 / depth as f32);
+                (x * tau * 2.0).sin() * (z * tau * 3.0).cos() + (x * tau).cos() * 0.5
+            }).collect();
+            Map::new(MapOptions {
+                source: MapSource::Heights { width, depth, data },
+                altitude_factor: 10.0,
+                wrap,
+                ..Default::default()
+            }).unwrap()
+        }
+
+        fn eroded_heights(seed: u64) -> Vec<f32> {
+            let mut map = hills(WrapMode::Repeat);
+            hydraulic_erosion(&mut map, &HydraulicErosion { droplets: 500, seed, ..Default::default() });
+            map.heights
+        }
+
+        #[test]
+        fn erosion_only_depends_on_the_seed() {
+            let heights = eroded_heights(7);
+            assert!(heights.iter().all(|height| height.is_finite()));
+            assert_eq!(heights, eroded_heights(7));
+            assert_ne!(heights, eroded_heights(8));
+        }
+
+        #[test]
+        fn repeated_map_stays_seamless() {
+            let base = hills(WrapMode::Repeat);
+            let mut map = hills(WrapMode::Repeat);
+            hydraulic_erosion(&mut map, &HydraulicErosion { droplets: 3000, ..Default::default() });
+            let (width, depth) = (map.width as i32, map.depth as i32);
+            // largest height step between neighbours, across the edges or within the map
+            let step = |map: &Map, across: bool| {
+                let mut step: f32 = 0.0;
+                for i in 0..depth {
+                    for j in 0..width {
+                        for (ni, nj) in [(i, j + 1), (i + 1, j)] {
+                            if (ni == depth || nj == width) == across {
+                                step = step.max((map.height_at_cell(i, j) - map.height_at_cell(ni, nj)).abs());
+                            }
+                        }
+                    }
+                }
+                step
+            };
+            // the edges were eroded like the inner cells, without creating a step between them
+            let edge_changed = (0..depth).any(|i| map.height_at_cell(i, 0) != base.height_at_cell(i, 0));
+            assert!(edge_changed);
+            assert!(step(&base, true) <= step(&base, false));
+            assert!(step(&map, true) <= step(&map, false), "{} > {}", step(&map, true), step(&map, false));
+        }
+
+        #[test]
+        fn clamped_edges_keep_the_sediment() {
+            let base = hills(WrapMode::Clamp);
+            let mut map = hills(WrapMode::Clamp);
+            hydraulic_erosion(&mut map, &HydraulicErosion { droplets: 3000, ..Default::default() });
+            let range = |map: &Map| map.heights.iter().fold((f32::MAX, f32::MIN), |(min, max), height| (min.min(*height), max.max(*height)));
+            let (base_min, base_max) = range(&base);
+            let (min, max) = range(&map);
+            assert!(min >= base_min - 1.0 && max <= base_max + 1.0, "{}..{} out of {}..{}", min, max, base_min, base_max);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+pub mod error {
+
+    use std::fmt;
+
+    ///
+    /// Errors returned while loading a map or building the terrain meshes.
+    ///
+    #[derive(Debug)]
+    pub enum TerrainError {
+        Io(std::io::Error),                                                 // the file can't be read
+        Decode(image::ImageError),                                          // the image can't be decoded
+        Encode(image::ImageError),                                          // the image can't be encoded or written
+        InvalidDimensions { width: usize, height: usize },                  // too few points
+        SizeMismatch { expected: usize, found: usize },                     // data, colors, mask or region not matching the map size
+        DimensionMismatch { expected: (usize, usize), found: (usize, usize) }, // image (width, height) not matching the map (width, depth)
+        RaggedPaths { path: usize, expected: usize, found: usize },         // a ribbon path hasn't the same number of points than the first one
+        MapSmallerThanTerrain { map_size: usize, terrain_size: usize },     // the terrain doesn't fit in the map
+        CacheVersion { expected: u32, found: u32 },                         // the map cache was written by another version of the format
+        InvalidCache(&'static str),                                         // the file isn't a map cache, or is truncated or corrupted
+        NonInvertibleHeights(&'static str),                                 // the map elevations can't be converted back to the source values
+    }
+
+    impl fmt::Display for TerrainError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TerrainError::Io(err) => write!(f, "i/o error: {}", err),
+                TerrainError::Decode(err) => write!(f, "image decoding error: {}", err),
+                TerrainError::Encode(err) => write!(f, "image encoding error: {}", err),
+                TerrainError::InvalidDimensions { width, height } => {
+                    write!(f, "invalid dimensions {}x{}: at least 2x2 points are required", width, height)
+                }
+                TerrainError::SizeMismatch { expected, found } => {
+                    write!(f, "size mismatch: {} expected, {} found", expected, found)
+                }
+                TerrainError::DimensionMismatch { expected, found } => {
+                    write!(f, "dimension mismatch: {}x{} expected, {}x{} found", expected.0, expected.1, found.0, found.1)
+                }
+                TerrainError::RaggedPaths { path, expected, found } => {
+                    write!(f, "path {} has {} points, {} expected: all the paths are required to have the same number of points", path, found, expected)
+                }
+                TerrainError::MapSmallerThanTerrain { map_size, terrain_size } => {
+                    write!(f, "a terrain of {} cells doesn't fit in a map of {} points", terrain_size, map_size)
+                }
+                TerrainError::CacheVersion { expected, found } => {
+                    write!(f, "map cache version {} found, version {} expected: the cache must be rebuilt", found, expected)
+                }
+                TerrainError::InvalidCache(reason) => write!(f, "invalid map cache: {}", reason),
+                TerrainError::NonInvertibleHeights(reason) => write!(f, "the map elevations can't be converted back to the source values: {}", reason),
+            }
+        }
+    }
+
+    impl std::error::Error for TerrainError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                TerrainError::Io(err) => Some(err),
+                TerrainError::Decode(err) => Some(err),
+                TerrainError::Encode(err) => Some(err),
+                _ => None,
+            }
+        }
+    }
+
+    impl From<std::io::Error> for TerrainError {
+        fn from(err: std::io::Error) -> Self {
+            TerrainError::Io(err)
+        }
+    }
+
+    impl From<image::ImageError> for TerrainError {
+        fn from(err: image::ImageError) -> Self {
+            TerrainError::Decode(err)
+        }
+    }
+}
@@ -0,0 +1,297 @@
+pub mod export {
+
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+    use three_d::{CpuMesh, InnerSpace, Vec2, Vec3};
+    use crate::error::error::TerrainError;
+
+    // mesh data shared by all the exporters
+    struct MeshData {
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<Vec2>>,
+        indices: Vec<u32>,
+    }
+
+    impl MeshData {
+        fn from(mesh: &CpuMesh) -> Self {
+            let positions = mesh.positions.to_f32();
+            let indices = mesh.indices.to_u32().unwrap_or_else(|| (0..positions.len() as u32).collect());
+            MeshData {
+                positions,
+                normals: mesh.normals.clone(),
+                uvs: mesh.uvs.clone(),
+                indices,
+            }
+        }
+    }
+
+    ///
+    /// Writes the mesh as a Wavefront OBJ file with its positions, uvs, normals and triangles.
+    ///
+    pub fn write_obj(mesh: &CpuMesh, path: &str) -> Result<(), TerrainError> {
+        let data = MeshData::from(mesh);
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "# dynamic_terrain")?;
+        for p in &data.positions {
+            writeln!(out, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        // OBJ texture coordinates start at the bottom of the image
+        if let Some(uvs) = &data.uvs {
+            for uv in uvs {
+                writeln!(out, "vt {} {}", uv.x, 1.0 - uv.y)?;
+            }
+        }
+        if let Some(normals) = &data.normals {
+            for n in normals {
+                writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+            }
+        }
+        let has_uvs = data.uvs.is_some();
+        let has_normals = data.normals.is_some();
+        for triangle in data.indices.chunks_exact(3) {
+            write!(out, "f")?;
+            for index in triangle {
+                let k = index + 1;
+                match (has_uvs, has_normals) {
+                    (true, true) => write!(out, " {}/{}/{}", k, k, k)?,
+                    (true, false) => write!(out, " {}/{}", k, k)?,
+                    (false, true) => write!(out, " {}//{}", k, k)?,
+                    (false, false) => write!(out, " {}", k)?,
+                }
+            }
+            writeln!(out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Writes the mesh triangles as a binary STL file, with their face normals.
+    ///
+    pub fn write_stl(mesh: &CpuMesh, path: &str) -> Result<(), TerrainError> {
+        let data = MeshData::from(mesh);
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&[0u8; 80])?;
+        out.write_all(&((data.indices.len() / 3) as u32).to_le_bytes())?;
+        for triangle in data.indices.chunks_exact(3) {
+            let a = data.positions[triangle[0] as usize];
+            let b = data.positions[triangle[1] as usize];
+            let c = data.positions[triangle[2] as usize];
+            let n = (b - a).cross(c - a);
+            let n = if n.magnitude2() > 0.0 { n.normalize() } else { n };
+            for v in [n, a, b, c] {
+                out.write_all(&v.x.to_le_bytes())?;
+                out.write_all(&v.y.to_le_bytes())?;
+                out.write_all(&v.z.to_le_bytes())?;
+            }
+            out.write_all(&0u16.to_le_bytes())?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    ///
+    /// Writes the mesh as a binary glTF 2.0 file (.glb) with its positions, normals, uvs and indices.
+    ///
+    pub fn write_gltf(mesh: &CpuMesh, path: &str) -> Result<(), TerrainError> {
+        let data = MeshData::from(mesh);
+        let mut bin: Vec<u8> = Vec::new();
+        let mut buffer_views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut attributes = Vec::new();
+        let vertex_count = data.positions.len();
+
+        // bounds of the positions, required by glTF
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for p in &data.positions {
+            for (k, value) in [p.x, p.y, p.z].iter().enumerate() {
+                min[k] = min[k].min(*value);
+                max[k] = max[k].max(*value);
+            }
+        }
+
+        // appends a buffer view and its accessor, returns the accessor index
+        let mut add_accessor = |bytes: Vec<u8>, count: usize, kind: &str, component_type: u32, target: u32, bounds: Option<(&[f32; 3], &[f32; 3])>| {
+            let offset = bin.len();
+            bin.extend_from_slice(&bytes);
+            while !bin.len().is_multiple_of(4) {
+                bin.push(0);
+            }
+            buffer_views.push(format!(
+                "{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{},\"target\":{}}}",
+                offset, bytes.len(), target
+            ));
+            let bounds = match bounds {
+                Some((min, max)) => format!(
+                    ",\"min\":[{},{},{}],\"max\":[{},{},{}]",
+                    min[0], min[1], min[2], max[0], max[1], max[2]
+                ),
+                None => String::new(),
+            };
+            accessors.push(format!(
+                "{{\"bufferView\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"{}}}",
+                buffer_views.len() - 1, component_type, count, kind, bounds
+            ));
+            accessors.len() - 1
+        };
+
+        let float = 5126;
+        let unsigned_int = 5125;
+        let array_buffer = 34962;
+        let element_array_buffer = 34963;
+        let vec3_bytes = |values: &[Vec3]| values.iter().flat_map(|v| [v.x, v.y, v.z]).flat_map(f32::to_le_bytes).collect::<Vec<u8>>();
+
+        let position = add_accessor(vec3_bytes(&data.positions), vertex_count, "VEC3", float, array_buffer, Some((&min, &max)));
+        attributes.push(format!("\"POSITION\":{}", position));
+        if let Some(normals) = &data.normals {
+            let normal = add_accessor(vec3_bytes(normals), vertex_count, "VEC3", float, array_buffer, None);
+            attributes.push(format!("\"NORMAL\":{}", normal));
+        }
+        if let Some(uvs) = &data.uvs {
+            let bytes = uvs.iter().flat_map(|uv| [uv.x, uv.y]).flat_map(f32::to_le_bytes).collect();
+            let uv = add_accessor(bytes, vertex_count, "VEC2", float, array_buffer, None);
+            attributes.push(format!("\"TEXCOORD_0\":{}", uv));
+        }
+        let bytes = data.indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let indices = add_accessor(bytes, data.indices.len(), "SCALAR", unsigned_int, element_array_buffer, None);
+
+        let mut json = format!(
+            "{{\"asset\":{{\"version\":\"2.0\",\"generator\":\"dynamic_terrain\"}},\"scene\":0,\"scenes\":[{{\"nodes\":[0]}}],\"nodes\":[{{\"mesh\":0}}],\
+             \"meshes\":[{{\"primitives\":[{{\"attributes\":{{{}}},\"indices\":{},\"mode\":4}}]}}],\
+             \"buffers\":[{{\"byteLength\":{}}}],\"bufferViews\":[{}],\"accessors\":[{}]}}",
+            attributes.join(","), indices, bin.len(), buffer_views.join(","), accessors.join(",")
+        );
+        while !json.len().is_multiple_of(4) {
+            json.push(' ');
+        }
+
+        // GLB container: header, JSON chunk and binary chunk
+        let total_length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"glTF")?;
+        out.write_all(&2u32.to_le_bytes())?;
+        out.write_all(&(total_length as u32).to_le_bytes())?;
+        out.write_all(&(json.len() as u32).to_le_bytes())?;
+        out.write_all(b"JSON")?;
+        out.write_all(json.as_bytes())?;
+        out.write_all(&(bin.len() as u32).to_le_bytes())?;
+        out.write_all(b"BIN\0")?;
+        out.write_all(&bin)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::ribbon::ribbon::create_ribbon;
+        use crate::test_utils::test_utils::temp_path;
+        use three_d::vec3;
+
+        // ribbon of 4 paths of 3 points: 12 vertices and 12 triangles
+        fn test_mesh() -> CpuMesh {
+            let paths: Vec<Vec<Vec3>> = (0..4).map(|i| (0..3).map(|j| vec3(j as f32, (i * j) as f32 * 0.5, i as f32)).collect()).collect();
+            create_ribbon(&paths, &[]).unwrap()
+        }
+
+        #[test]
+        fn obj_round_trip() {
+            let mesh = test_mesh();
+            let path = temp_path("mesh.obj");
+            write_obj(&mesh, &path).unwrap();
+            let text = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let count = |prefix: &str| text.lines().filter(|line| line.starts_with(prefix)).count();
+            assert_eq!((count("v "), count("vt "), count("vn "), count("f ")), (12, 12, 12, 12));
+            let positions: Vec<Vec3> = text.lines().filter_map(|line| line.strip_prefix("v ")).map(|values| {
+                let v: Vec<f32> = values.split(' ').map(|value| value.parse().unwrap()).collect();
+                vec3(v[0], v[1], v[2])
+            }).collect();
+            assert_eq!(positions, mesh.positions.to_f32());
+            let faces: Vec<u32> = text.lines().filter_map(|line| line.strip_prefix("f ")).flat_map(|face| {
+                face.split(' ').map(|vertex| vertex.split('/').next().unwrap().parse::<u32>().unwrap() - 1).collect::<Vec<_>>()
+            }).collect();
+            assert_eq!(faces, mesh.indices.to_u32().unwrap());
+            // the texture coordinates are flipped in v
+            let uvs: Vec<Vec2> = text.lines().filter_map(|line| line.strip_prefix("vt ")).map(|values| {
+                let v: Vec<f32> = values.split(' ').map(|value| value.parse().unwrap()).collect();
+                Vec2::new(v[0], 1.0 - v[1])
+            }).collect();
+            let expected = mesh.uvs.as_ref().unwrap();
+            assert!(expected.iter().any(|uv| uv.y != 0.5));
+            for (uv, expected) in uvs.iter().zip(expected) {
+                assert!((uv - expected).magnitude() < 1e-6, "{:?} != {:?}", uv, expected);
+            }
+        }
+
+        #[test]
+        fn stl_round_trip() {
+            let mesh = test_mesh();
+            let path = temp_path("mesh.stl");
+            write_stl(&mesh, &path).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let triangles = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+            assert_eq!(triangles, 12);
+            assert_eq!(bytes.len(), 84 + 50 * 12);
+            let float = |offset: usize| f32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+            let vector = |offset: usize| Vec3::new(float(offset), float(offset + 4), float(offset + 8));
+            let positions = mesh.positions.to_f32();
+            let indices = mesh.indices.to_u32().unwrap();
+            for (t, triangle) in indices.chunks_exact(3).enumerate() {
+                let offset = 84 + 50 * t;
+                let [a, b, c] = [0, 1, 2].map(|k| positions[triangle[k] as usize]);
+                assert_eq!([vector(offset + 12), vector(offset + 24), vector(offset + 36)], [a, b, c]);
+                assert!((vector(offset) - (b - a).cross(c - a).normalize()).magnitude() < 1e-6);
+            }
+        }
+
+        #[test]
+        fn gltf_round_trip() {
+            let mesh = test_mesh();
+            let path = temp_path("mesh.glb");
+            write_gltf(&mesh, &path).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let word = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize;
+            assert_eq!(&bytes[0..4], b"glTF");
+            assert_eq!(word(8), bytes.len());
+            let json_length = word(12);
+            assert_eq!(&bytes[16..20], b"JSON");
+            let json = std::str::from_utf8(&bytes[20..20 + json_length]).unwrap();
+            let bin_length = word(20 + json_length);
+            assert_eq!(&bytes[24 + json_length..28 + json_length], b"BIN\0");
+            assert_eq!(28 + json_length + bin_length, bytes.len());
+            let bin = &bytes[28 + json_length..];
+
+            // values of all the occurrences of a key in the JSON chunk, in order
+            let values = |key: &str| -> Vec<usize> {
+                json.split(&format!("\"{}\":", key)).skip(1).map(|rest| {
+                    rest[..rest.find(|c: char| !c.is_ascii_digit()).unwrap()].parse().unwrap()
+                }).collect()
+            };
+            let views = values("bufferView");
+            let offsets = values("byteOffset");
+            // the first byte length is the one of the whole buffer
+            let lengths = &values("byteLength")[1..];
+            // the 32-bit words of the buffer view of an accessor
+            let accessor_words = |accessor: usize| -> Vec<[u8; 4]> {
+                let view = views[accessor];
+                bin[offsets[view]..offsets[view] + lengths[view]].chunks_exact(4).map(|b| [b[0], b[1], b[2], b[3]]).collect()
+            };
+            let floats = |accessor: usize| -> Vec<f32> { accessor_words(accessor).into_iter().map(f32::from_le_bytes).collect() };
+            let vectors = |accessor: usize| -> Vec<Vec3> { floats(accessor).chunks_exact(3).map(|v| Vec3::new(v[0], v[1], v[2])).collect() };
+
+            // 12 vertices and 36 indices
+            assert_eq!(values("count"), vec![12, 12, 12, 36]);
+            assert_eq!(vectors(values("POSITION")[0]), mesh.positions.to_f32());
+            assert_eq!(&vectors(values("NORMAL")[0]), mesh.normals.as_ref().unwrap());
+            let uvs: Vec<Vec2> = floats(values("TEXCOORD_0")[0]).chunks_exact(2).map(|v| Vec2::new(v[0], v[1])).collect();
+            assert_eq!(&uvs, mesh.uvs.as_ref().unwrap());
+            let indices: Vec<u32> = accessor_words(values("indices")[0]).into_iter().map(u32::from_le_bytes).collect();
+            assert_eq!(indices, mesh.indices.to_u32().unwrap());
+        }
+    }
+}
@@ -0,0 +1,293 @@
+pub mod filters {
+
+    use crate::dt::terrain::Map;
+    use crate::error::error::TerrainError;
+    use crate::source::source::HeightSource;
+
+    ///
+    /// Parameters of the thermal erosion: the material slumps down the slopes steeper than the talus angle.
+    ///
+    pub struct ThermalErosion {
+        pub iterations: usize,
+        pub talus_angle: f32,       // steepest stable slope, in degrees
+        pub rate: f32,              // fraction of the excess material moved at each iteration, in 0..0.5
+    }
+
+    impl Default for ThermalErosion {
+        fn default() -> Self {
+            ThermalErosion {
+                iterations: 50,
+                talus_angle: 35.0,
+                rate: 0.5,
+            }
+        }
+    }
+
+    ///
+    /// Smoothing kernels of the map heights.
+    ///
+    pub enum SmoothingKernel {
+        Box { radius: usize },      // average of the (2 * radius + 1)² neighbourhood
+        Gaussian { sigma: f32 },    // gaussian weights, in cells, the neighbourhood extends to 3 sigma
+    }
+
+    // the mask gives a weight in 0..1 to each map point, row by row like the heights
+    fn check_mask(map: &Map, mask: Option<&[f32]>) -> Result<(), TerrainError> {
+        match mask {
+            Some(mask) if mask.len() != map.heights.len() => Err(TerrainError::SizeMismatch { expected: map.heights.len(), found: mask.len() }),
+            _ => Ok(()),
+        }
+    }
+
+    // replaces the map heights by the filtered ones, blended by the mask weights, and updates the precomputed normals
+    fn apply_heights(map: &mut Map, heights: Vec<f32>, mask: Option<&[f32]>) {
+        match mask {
+            Some(mask) => {
+                for ((height, filtered), weight) in map.heights.iter_mut().zip(heights).zip(mask) {
+                    *height += (filtered - *height) * weight.clamp(0.0, 1.0);
+                }
+            }
+            None => map.heights = heights,
+        }
+        if !map.normals.is_empty() {
+            map.compute_normals();
+        }
+    }
+
+    ///
+    /// Moves the material of the slopes steeper than the talus angle down to the lower neighbours, in place.
+    /// The neighbourhoods follow the map wrap mode, "mask" optionally limits the erosion to a region:
+    /// the material only moves between the cells of the mask, in proportion to the weights of both cells.
+    ///
+    pub fn thermal_erosion(map: &mut Map, settings: &ThermalErosion, mask: Option<&[f32]>) -> Result<(), TerrainError> {
+        check_mask(map, mask)?;
+        let (width, depth) = (map.width, map.depth);
+        let talus = settings.talus_angle.to_radians().tan();
+        // 8 neighbours and their stable height difference
+        let mut neighbours = Vec::new();
+        for di in -1i32..=1 {
+            for dj in -1i32..=1 {
+                if di != 0 || dj != 0 {
+                    let distance = ((dj as f32 * map.spacing.x).powi(2) + (di as f32 * map.spacing.y).powi(2)).sqrt();
+                    neighbours.push((di, dj, talus * distance));
+                }
+            }
+        }
+        let mut deltas = vec![0.0; width * depth];
+        let mut lower = Vec::with_capacity(neighbours.len());
+        for _ in 0..settings.iterations {
+            deltas.iter_mut().for_each(|delta| *delta = 0.0);
+            for i in 0..depth as i32 {
+                for j in 0..width as i32 {
+                    let index = i as usize * width + j as usize;
+                    let weight = mask.map(|mask| mask[index].clamp(0.0, 1.0)).unwrap_or(1.0);
+                    if weight == 0.0 {
+                        continue;
+                    }
+                    let height = map.heights[index];
+                    // excess height differences towards the lower neighbours weighted by their mask,
+                    // the neighbours beyond the edges of a clamped map get nothing
+                    lower.clear();
+                    let mut total = 0.0;
+                    let mut max_excess: f32 = 0.0;
+                    for (di, dj, stable) in &neighbours {
+                        let Some(neighbour) = map.cell_index(i + di, j + dj) else {
+                            continue;
+                        };
+                        let neighbour_weight = mask.map(|mask| mask[neighbour].clamp(0.0, 1.0)).unwrap_or(1.0);
+                        let excess = (height - map.heights[neighbour] - stable) * neighbour_weight;
+                        if excess > 0.0 {
+                            lower.push((neighbour, excess));
+                            total += excess;
+                            max_excess = max_excess.max(excess);
+                        }
+                    }
+                    if total == 0.0 {
+                        continue;
+                    }
+                    let moved = max_excess * settings.rate * weight;
+                    deltas[index] -= moved;
+                    for (neighbour, excess) in &lower {
+                        deltas[*neighbour] += moved * excess / total;
+                    }
+                }
+            }
+            for (height, delta) in map.heights.iter_mut().zip(&deltas) {
+                *height += delta;
+            }
+        }
+        if !map.normals.is_empty() {
+            map.compute_normals();
+        }
+        Ok(())
+    }
+
+    ///
+    /// Smooths the map heights with a box or gaussian kernel, in place.
+    /// The neighbourhoods follow the map wrap mode, "mask" optionally blends the result with the original heights.
+    ///
+    pub fn smooth(map: &mut Map, kernel: &SmoothingKernel, mask: Option<&[f32]>) -> Result<(), TerrainError> {
+        check_mask(map, mask)?;
+        let weights: Vec<f32> = match kernel {
+            SmoothingKernel::Box { radius } => vec![1.0; 2 * radius + 1],
+            SmoothingKernel::Gaussian { sigma } => {
+                let sigma = sigma.max(1e-3);
+                let radius = (3.0 * sigma).ceil() as i32;
+                (-radius..=radius).map(|k| (-(k * k) as f32 / (2.0 * sigma * sigma)).exp()).collect()
+            }
+        };
+        let sum: f32 = weights.iter().sum();
+        let radius = (weights.len() / 2) as i32;
+        let (width, depth) = (map.width as i32, map.depth as i32);
+        // separable kernel: along x, then along z
+        let mut rows = vec![0.0; map.heights.len()];
+        for i in 0..depth {
+            for j in 0..width {
+                let value: f32 = weights.iter().enumerate().map(|(k, w)| w * map.height_at_cell(i, j + k as i32 - radius)).sum();
+                rows[(i * width + j) as usize] = value / sum;
+            }
+        }
+        let mut heights = vec![0.0; map.heights.len()];
+        for i in 0..depth {
+            for j in 0..width {
+                let value: f32 = weights.iter().enumerate().map(|(k, w)| {
+                    let (ni, nj) = map.wrap_cell(i + k as i32 - radius, j);
+                    w * rows[(ni * width + nj) as usize]
+                }).sum();
+                heights[(i * width + j) as usize] = value / sum;
+            }
+        }
+        apply_heights(map, heights, mask);
+        Ok(())
+    }
+
+    ///
+    /// Replaces each map height by the median of its (2 * radius + 1)² neighbourhood, in place.
+    /// Removes the spikes and the steps of the 8-bit heightmaps while keeping the ridges sharper than smooth().
+    ///
+    pub fn median_filter(map: &mut Map, radius: usize, mask: Option<&[f32]>) -> Result<(), TerrainError> {
+        check_mask(map, mask)?;
+        let radius = radius as i32;
+        let (width, depth) = (map.width as i32, map.depth as i32);
+        let mut heights = vec![0.0; map.heights.len()];
+        let mut window = Vec::with_capacity(((2 * radius + 1) * (2 * radius + 1)) as usize);
+        for i in 0..depth {
+            for j in 0..width {
+                window.clear();
+                for di in -radius..=radius {
+                    for dj in -radius..=radius {
+                        window.push(map.height_at_cell(i + di, j + dj));
+                    }
+                }
+                let middle = window.len() / 2;
+                let (_, median, _) = window.select_nth_unstable_by(middle, f32::total_cmp);
+                heights[(i * width + j) as usize] = *median;
+            }
+        }
+        apply_heights(map, heights, mask);
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::dt::terrain::{MapOptions, MapSource};
+        use crate::source::source::WrapMode;
+        use three_d::{vec2, vec3};
+
+        // width x depth flat map of height 1, one unit apart, with a spike of height 10 at the cell (i, j)
+        fn spiked_map(width: usize, depth: usize, i: usize, j: usize, wrap: WrapMode) -> Map {
+            let mut data = vec![1.0; width * depth];
+            data[i * width + j] = 10.0;
+            Map::new(MapOptions {
+                source: MapSource::Heights { width, depth, data },
+                spacing: vec2(1.0, 1.0),
+                altitude_factor: 1.0,
+                origin: vec3(0.0, 0.0, 0.0),
+                wrap,
+                ..Default::default()
+            }).unwrap()
+        }
+
+        fn total(map: &Map) -> f32 {
+            map.heights.iter().sum()
+        }
+
+        #[test]
+        fn box_filter_averages_a_spike() {
+            let mut map = spiked_map(5, 5, 2, 2, WrapMode::Repeat);
+            smooth(&mut map, &SmoothingKernel::Box { radius: 1 }, None).unwrap();
+            for i in 0..5usize {
+                for j in 0..5usize {
+                    let expected = if i.abs_diff(2) <= 1 && j.abs_diff(2) <= 1 { 2.0 } else { 1.0 };
+                    assert!((map.height(i, j) - expected).abs() < 1e-5, "({}, {}): {}", i, j, map.height(i, j));
+                }
+            }
+        }
+
+        #[test]
+        fn median_filter_removes_a_single_cell_spike() {
+            let mut map = spiked_map(5, 5, 2, 2, WrapMode::Repeat);
+            median_filter(&mut map, 1, None).unwrap();
+            assert!(map.heights.iter().all(|height| *height == 1.0));
+        }
+
+        #[test]
+        fn zero_mask_leaves_the_heights_unchanged() {
+            let original = spiked_map(5, 5, 2, 2, WrapMode::Repeat).heights;
+            let mask = vec![0.0; 25];
+            let mut map = spiked_map(5, 5, 2, 2, WrapMode::Repeat);
+            smooth(&mut map, &SmoothingKernel::Gaussian { sigma: 1.0 }, Some(&mask)).unwrap();
+            median_filter(&mut map, 1, Some(&mask)).unwrap();
+            thermal_erosion(&mut map, &ThermalErosion::default(), Some(&mask)).unwrap();
+            assert_eq!(map.heights, original);
+        }
+
+        #[test]
+        fn edges_follow_the_wrap_mode() {
+            // the neighbourhood of the corner spike wraps to the opposite corner, or is clamped on the spike
+            let mut repeat = spiked_map(5, 5, 0, 0, WrapMode::Repeat);
+            smooth(&mut repeat, &SmoothingKernel::Box { radius: 1 }, None).unwrap();
+            assert!((repeat.height(4, 4) - 2.0).abs() < 1e-5);
+            assert!((repeat.height(0, 0) - 2.0).abs() < 1e-5);
+            let mut clamp = spiked_map(5, 5, 0, 0, WrapMode::Clamp);
+            smooth(&mut clamp, &SmoothingKernel::Box { radius: 1 }, None).unwrap();
+            assert_eq!(clamp.height(4, 4), 1.0);
+            assert!((clamp.height(0, 0) - 5.0).abs() < 1e-5);
+        }
+
+        #[test]
+        fn thermal_erosion_skips_the_neighbours_beyond_a_clamped_edge() {
+            let mut map = spiked_map(5, 5, 0, 2, WrapMode::Clamp);
+            let before = total(&map);
+            thermal_erosion(&mut map, &ThermalErosion { iterations: 1, ..Default::default() }, None).unwrap();
+            assert!((total(&map) - before).abs() < 1e-4);
+            assert!(map.height(0, 2) < 10.0);
+            // the straight neighbours get the same share, more than the diagonal ones
+            let straight = map.height(0, 1);
+            assert!((map.height(0, 3) - straight).abs() < 1e-5);
+            assert!((map.height(1, 2) - straight).abs() < 1e-5);
+            let diagonal = map.height(1, 1);
+            assert!((map.height(1, 3) - diagonal).abs() < 1e-5);
+            assert!(diagonal > 1.0 && diagonal < straight);
+            assert_eq!(map.height(4, 2), 1.0);
+        }
+
+        #[test]
+        fn thermal_erosion_keeps_the_material_inside_the_mask() {
+            let mut map = spiked_map(5, 5, 2, 2, WrapMode::Repeat);
+            let mut mask = vec![0.0; 25];
+            mask[2 * 5 + 2] = 1.0;
+            mask[2 * 5 + 3] = 1.0;
+            let before = total(&map);
+            thermal_erosion(&mut map, &ThermalErosion { iterations: 1, ..Default::default() }, Some(&mask)).unwrap();
+            assert!((total(&map) - before).abs() < 1e-4);
+            assert!(map.height(2, 3) > 1.0);
+            for (index, height) in map.heights.iter().enumerate() {
+                if mask[index] == 0.0 {
+                    assert_eq!(*height, 1.0, "cell {}", index);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,14 @@
+#![allow(clippy::module_inception)]
+
+pub mod ribbon;
+pub mod dt;
+pub mod wireframe;
+pub mod error;
+pub mod export;
+pub mod noise;
+pub mod source;
+pub mod erosion;
+pub mod filters;
+
+#[cfg(test)]
+mod test_utils;
@@ -1,6 +1,4 @@
-pub mod ribbon;
-pub mod dt;
-pub mod wireframe;
+use dynamic_terrain::dt;
 
 // Entry point for non-wasm
 #[cfg(not(target_arch = "wasm32"))]
@@ -10,12 +8,13 @@ async fn main() {
 }
 
 
-use crate::wireframe::wireframe::apply_wireframe;
 use three_d::*;
 use std::rc::Rc;
 
+const HEIGHTMAPFILE : &str = "assets/worldHeightMapDouble.png";
 const GROUNDFILE : &str = "assets/earthDouble.png";
 const GROUNDASSET : &str = "earthDouble";
+const GROUNDCLEARANCE : f32 = 10.0;
 const CLEARCOLOR : (f32, f32, f32, f32, f32) = (0.7, 0.8, 0.98, 1.0, 1.0);
 
 
@@ -65,20 +64,42 @@ pub async fn run() {
     };
     
 
-    let map = Rc::new(dt::terrain::Map::new());
-    let mut terrain = dt::terrain::Terrain::new(&context, Rc::clone(&map), 300, cpu_material_terrain);
-    //let mut wireframe = apply_wireframe(&context, &map_mesh);
+    let map_options = dt::terrain::MapOptions {
+        source: dt::terrain::MapSource::File(HEIGHTMAPFILE.to_string()),
+        spacing: vec2(5.0, 5.0),
+        altitude_factor: 80.0,
+        compute_normals: true,
+        ..Default::default()
+    };
+    let map = match dt::terrain::Map::new(map_options) {
+        Ok(map) => Rc::new(map),
+        Err(err) => {
+            eprintln!("Failed to load the map: {}", err);
+            return;
+        }
+    };
+    let mut terrain = match dt::terrain::Terrain::new(&context, map, 300, cpu_material_terrain) {
+        Ok(terrain) => terrain,
+        Err(err) => {
+            eprintln!("Failed to create the terrain: {}", err);
+            return;
+        }
+    };
+    //let mut wireframe = wireframe::wireframe::apply_wireframe(&context, &map_mesh);
     //wireframe.set_transformation(Matrix4::from_translation(vec3(0.0, -500.0, 0.0))); // slide down the wireframe
 
 
-    terrain.camera_pos.x = terrain.position.x;
-    terrain.camera_pos.z = terrain.position.z;
+    terrain.sampler.camera_pos.x = terrain.sampler.position.x;
+    terrain.sampler.camera_pos.z = terrain.sampler.position.z;
+    terrain.sampler.lod_limits = vec![80, 120];
+    terrain.sampler.lod_morph_band = 16;
+    terrain.sampler.lod_camera_altitude = Some(Box::new(|altitude| if altitude > 200.0 { 2 } else { 1 }));
+    terrain.sampler.lod_global_morph_frames = 30;
+    terrain.update_mesh();
 
     let speed: f32 = 4.0 ;
     let delta_ang_y: f32 = speed * 0.008;   // roll speed
     let mut ang_y: f32 = 0.0;
-    let mut ang_x: f32 = 0.0;
-    let mut ang_z: f32 = 0.0;
     let dir = camera.view_direction();
     let cam_up  = camera.up();
 
@@ -86,31 +107,31 @@ pub async fn run() {
     let mut pointer_distance_x = 0.0;
     let mut pointer_distance_y = 0.0;
 
-    window.render_loop(move |mut frame_input| {
+    window.render_loop(move |frame_input| {
         camera.set_viewport(frame_input.viewport);
         //control.handle_events(&mut camera, &mut frame_input.events);
         
-        if terrain.camera_pos.y < 1.0 {
-            terrain.camera_pos.y = 10.0;
-            println!("camera_pos.y < 10.0");
+        let ground = terrain.get_height_at(terrain.sampler.camera_pos.x, terrain.sampler.camera_pos.z);
+        if terrain.sampler.camera_pos.y < ground + GROUNDCLEARANCE {
+            terrain.sampler.camera_pos.y = ground + GROUNDCLEARANCE;
         }
-        if terrain.camera_pos.y > 300.0 {
-            terrain.camera_pos.y = 280.0;
+        if terrain.sampler.camera_pos.y > 300.0 {
+            terrain.sampler.camera_pos.y = 280.0;
             println!("camera_pos.y > 300.0");
         }
 
 
         for event in frame_input.events.iter() {
 
-            if let Event::MouseMotion {button: _, delta, position, modifiers: _, handled: _ } = *event {
+            if let Event::MouseMotion {button: _, delta: _, position, modifiers: _, handled: _ } = *event {
                 let width = frame_input.viewport.width as f32;
                 let height = frame_input.viewport.height as f32;
                 pointer_distance_x = (1.0 - 2.0 *  position.x / width) * 0.5;
                 pointer_distance_y = (1.0 - 2.0 * position.y / height) * 0.5;
             } 
         }
-        ang_x = pointer_distance_y.atan();
-        ang_z = pointer_distance_x.atan(); 
+        let ang_x = pointer_distance_y.atan();
+        let ang_z = pointer_distance_x.atan();
         ang_y += delta_ang_y * ang_z;
         let cam_pos = camera.position();
 
@@ -118,7 +139,7 @@ pub async fn run() {
         let rot_y = Matrix3::from_angle_y(Rad(ang_y));
         let rot_z = Matrix3::from_angle_z(Rad(ang_z * 0.75));
         let rotation = rot_y * rot_x * rot_z;
-        let mut rotated_dir = rotation * dir;
+        let rotated_dir = rotation * dir;
 
         let rotated_up = rotation * cam_up;
         camera.set_view(
@@ -130,7 +151,7 @@ pub async fn run() {
 
 
         let direction = camera.view_direction().normalize_to(speed);
-        terrain.camera_pos -= direction;
+        terrain.sampler.camera_pos += direction;
 
 
         terrain.update();
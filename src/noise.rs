@@ -0,0 +1,172 @@
+pub mod noise {
+
+    use fastnoise_lite::*;
+    use three_d::{vec2, Vec2, Vec3};
+    use crate::source::source::HeightSource;
+
+    ///
+    /// Parameters of a procedural noise layer.
+    /// Several layers can be stacked, each one scaled by its amplitude.
+    ///
+    pub struct NoiseSettings {
+        pub seed: i32,
+        pub noise_type: NoiseType,
+        pub frequency: f32,
+        pub fractal_type: FractalType,          // FractalType::None, FBm, Ridged or PingPong
+        pub octaves: i32,                       // number of fractal octaves
+        pub lacunarity: f32,                    // frequency multiplier between two successive octaves
+        pub gain: f32,                          // amplitude multiplier between two successive octaves
+        pub ping_pong_strength: f32,            // used by FractalType::PingPong only
+        pub warp_type: Option<DomainWarpType>,  // domain warp applied to the sample coordinates, None to disable it
+        pub warp_amplitude: f32,                // maximal displacement of the warped coordinates, in map points
+        pub warp_frequency: f32,
+        pub amplitude: f32,                     // the layer values are in 0..amplitude
+    }
+
+    impl Default for NoiseSettings {
+        fn default() -> Self {
+            NoiseSettings {
+                seed: 20,
+                noise_type: NoiseType::OpenSimplex2,
+                frequency: 0.015,
+                fractal_type: FractalType::None,
+                octaves: 3,
+                lacunarity: 2.0,
+                gain: 0.5,
+                ping_pong_strength: 2.0,
+                warp_type: None,
+                warp_amplitude: 30.0,
+                warp_frequency: 0.005,
+                amplitude: 1.0,
+            }
+        }
+    }
+
+    // noise generators of a layer
+    struct NoiseLayer {
+        noise: FastNoiseLite,
+        warp: Option<FastNoiseLite>,
+        amplitude: f32,
+    }
+
+    ///
+    /// Sums the noise layers built from a list of NoiseSettings, at any point of the plane.
+    ///
+    pub struct NoiseGenerator {
+        layers: Vec<NoiseLayer>,
+    }
+
+    impl NoiseGenerator {
+        pub fn new(settings: &[NoiseSettings]) -> Self {
+            let layers = settings.iter().map(|settings| {
+                let mut noise = FastNoiseLite::with_seed(settings.seed);
+                noise.set_noise_type(Some(settings.noise_type));
+                noise.set_frequency(Some(settings.frequency));
+                noise.set_fractal_type(Some(settings.fractal_type));
+                noise.set_fractal_octaves(Some(settings.octaves));
+                noise.set_fractal_lacunarity(Some(settings.lacunarity));
+                noise.set_fractal_gain(Some(settings.gain));
+                noise.set_fractal_ping_pong_strength(Some(settings.ping_pong_strength));
+                // the warp has its own generator, the fractal type of the noise doesn't apply to it
+                let warp = settings.warp_type.map(|warp_type| {
+                    let mut warp = FastNoiseLite::with_seed(settings.seed);
+                    warp.set_domain_warp_type(Some(warp_type));
+                    warp.set_domain_warp_amp(Some(settings.warp_amplitude));
+                    warp.set_frequency(Some(settings.warp_frequency));
+                    warp
+                });
+                NoiseLayer { noise, warp, amplitude: settings.amplitude }
+            }).collect();
+            NoiseGenerator { layers }
+        }
+
+        ///
+        /// Returns the sum of the layers at the point (x, z), each layer being remapped from -1..1 to 0..amplitude.
+        ///
+        pub fn get_noise(&self, x: f32, z: f32) -> f32 {
+            self.layers.iter().map(|layer| {
+                let (x, z) = match &layer.warp {
+                    Some(warp) => warp.domain_warp_2d(x, z),
+                    None => (x, z),
+                };
+                (layer.noise.get_noise_2d(x, z) + 1.0) * 0.5 * layer.amplitude
+            }).sum()
+        }
+    }
+
+    ///
+    /// Endless height source computed from noise layers, for procedural worlds that never repeat.
+    /// The heights only depend on the layers settings and on the cell, so all the clients using the same seeds get the same world.
+    ///
+    pub struct NoiseSource {
+        generator: NoiseGenerator,
+        pub spacing: Vec2,          // distance between two successive cells on the x axis (x) and on the z axis (y)
+        pub altitude_factor: f32,   // elevation scale applied to the noise values
+        pub origin: Vec3,           // map coordinates of the cell (0, 0), the y value is added to the elevations
+        pub uv_tiles: Vec2,         // number of cells covered by the texture on the x axis (x) and on the z axis (y)
+    }
+
+    impl NoiseSource {
+        pub fn new(layers: &[NoiseSettings], spacing: Vec2, altitude_factor: f32) -> Self {
+            NoiseSource {
+                generator: NoiseGenerator::new(layers),
+                spacing,
+                altitude_factor,
+                origin: Vec3::new(0.0, 0.0, 0.0),
+                uv_tiles: vec2(256.0, 256.0),
+            }
+        }
+    }
+
+    impl HeightSource for NoiseSource {
+        fn height_at_cell(&self, i: i32, j: i32) -> f32 {
+            self.generator.get_noise(j as f32, i as f32) * self.altitude_factor + self.origin.y
+        }
+
+        // the texture repeats every uv_tiles cells, the uvs aren't wrapped so that the quads don't cross the texture backwards
+        fn uv_at_cell(&self, i: i32, j: i32) -> Vec2 {
+            vec2(j as f32 / self.uv_tiles.x, 1.0 - i as f32 / self.uv_tiles.y)
+        }
+
+        fn spacing(&self) -> Vec2 {
+            self.spacing
+        }
+
+        fn cell_origin(&self) -> Vec2 {
+            vec2(self.origin.x, self.origin.z)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn layer(seed: i32, amplitude: f32) -> NoiseSettings {
+            NoiseSettings { seed, amplitude, fractal_type: FractalType::FBm, warp_type: Some(DomainWarpType::OpenSimplex2), ..Default::default() }
+        }
+
+        fn sample(generator: &NoiseGenerator) -> Vec<f32> {
+            (0..32).flat_map(|i| (0..32).map(move |j| (i, j))).map(|(i, j)| generator.get_noise(j as f32 * 3.0, i as f32 * 3.0)).collect()
+        }
+
+        #[test]
+        fn same_settings_give_the_same_heights() {
+            let heights = sample(&NoiseGenerator::new(&[layer(7, 1.0)]));
+            assert_eq!(heights, sample(&NoiseGenerator::new(&[layer(7, 1.0)])));
+            assert_ne!(heights, sample(&NoiseGenerator::new(&[layer(8, 1.0)])));
+        }
+
+        #[test]
+        fn layer_amplitudes_add_up() {
+            let first = sample(&NoiseGenerator::new(&[layer(7, 1.0)]));
+            let second = sample(&NoiseGenerator::new(&[layer(9, 3.0)]));
+            let both = sample(&NoiseGenerator::new(&[layer(7, 1.0), layer(9, 3.0)]));
+            for ((a, b), sum) in first.iter().zip(&second).zip(&both) {
+                assert!((a + b - sum).abs() < 1e-5);
+                assert!((0.0..=4.0).contains(sum));
+            }
+            assert!(first.iter().all(|value| (0.0..=1.0).contains(value)));
+            assert!(second.iter().any(|value| *value > 1.0));
+        }
+    }
+}
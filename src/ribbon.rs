@@ -6,30 +6,30 @@ pub mod ribbon {
     use three_d::Vec2;
     use three_d_asset::TriMesh;
     use three_d::Vec3;
+    use three_d::Vec4;
     use three_d::vec2;
     use three_d::InnerSpace;
+    use crate::error::error::TerrainError;
     ///
     /// Returns a ribbon mesh from the passed parameter "paths"
     /// Paths is a vector of paths, where each path is a vector of Vec3.
     /// Each path is a list of successive points. Paths will be connected with triangles to make a surface or a volume.
     /// All the paths are required to have the same number of points.
-    /// At leas        ///t two paths are required to create a ribbon.
+    /// At least two paths are required to create a ribbon.
     /// At least each path should have two points.
+    /// The triangles are wound so that paths along +x stacked along +z, like the terrain rows, face up (+y).
+    /// Returns an error if these requirements aren't met.
     /// 
-    pub fn create_ribbon(paths: &Vec<Vec<Vec3>>, map_uvs: &Vec<Vec2>) -> TriMesh {
+    pub fn create_ribbon(paths: &[Vec<Vec3>], map_uvs: &[Vec2]) -> Result<TriMesh, TerrainError> {
         // path lengths
         let p = paths.len();
-        if p < 2 {
-            panic!("At least two paths are required to create a ribbon");
+        let l = if p > 0 { paths[0].len() } else { 0 };
+        if p < 2 || l < 2 {
+            return Err(TerrainError::InvalidDimensions { width: l, height: p });
         }
-        let l = paths[0].len();
-        if l < 2 {
-            panic!("At least each path should have two points");
-            
-        }
-        for i in 1..p {
-            if paths[i].len() != l {
-                panic!("All the paths are required to have the same number of points");
+        for (i, path) in paths.iter().enumerate().skip(1) {
+            if path.len() != l {
+                return Err(TerrainError::RaggedPaths { path: i, expected: l, found: path.len() });
             }
         }
 
@@ -39,7 +39,7 @@ pub mod ribbon {
         // uvs coordinates are computed according to the distance between path points if the passed Vector is empty
         let mut map_uvs_given = true;
         let mut ribbon_uvs = Vec::new();
-        if map_uvs.len() == 0 {
+        if map_uvs.is_empty() {
             map_uvs_given = false;
         }
 
@@ -53,10 +53,10 @@ pub mod ribbon {
         for i in 0..p {
             u_total_distance = 0.0;
             for j in 0..l {
-                let v3 = paths[i][j].clone();
+                let v3 = paths[i][j];
                 positions.push(v3);
                 if map_uvs_given {
-                    ribbon_uvs.push(map_uvs[i * l + j].clone());
+                    ribbon_uvs.push(map_uvs[i * l + j]);
                 }
                 else if j > 0 {
                     u_total_distance += (paths[i][j] - paths[i][j - 1]).magnitude();
@@ -97,12 +97,12 @@ pub mod ribbon {
                 let j1 = (i + 1) * l + j + 1;
 
                 indices.push(i0 as u32);
-                indices.push(i1 as u32);
                 indices.push(j1 as u32);
+                indices.push(i1 as u32);
 
                 indices.push(j1 as u32);
-                indices.push(j0 as u32);
                 indices.push(i0 as u32);
+                indices.push(j0 as u32);
             }
         }
 
@@ -115,23 +115,140 @@ pub mod ribbon {
         };
         mesh.compute_normals();
         mesh.compute_tangents();
-        mesh
+        Ok(mesh)
     }
 
 
-    pub fn morph_ribbon(mesh: &mut Mesh, paths: &mut &Vec<Vec<Vec3>>, uvs: &Vec<Vec2>) {
+    ///
+    /// Returns the vertex normals of the ribbon built from "paths".
+    /// They are computed from the grid structure with the same triangles and winding than create_ribbon(),
+    /// so they match what TriMesh::compute_normals() returns without building the index buffer.
+    ///
+    pub fn compute_ribbon_normals(paths: &[Vec<Vec3>]) -> Vec<Vec3> {
+        let p = paths.len();
+        let l = if p > 0 { paths[0].len() } else { 0 };
+        let mut normals = vec![Vec3::new(0.0, 0.0, 0.0); p * l];
+        for i in 0..p.saturating_sub(1) {
+            for j in 0..l.saturating_sub(1) {
+                let i0 = i * l + j;
+                let i1 = i * l + j + 1;
+                let j0 = (i + 1) * l + j;
+                let j1 = (i + 1) * l + j + 1;
+                let pi0 = paths[i][j];
+                let pi1 = paths[i][j + 1];
+                let pj0 = paths[i + 1][j];
+                let pj1 = paths[i + 1][j + 1];
+
+                // triangle i0, j1, i1
+                let n = (pj1 - pi0).cross(pi1 - pi0);
+                normals[i0] += n;
+                normals[i1] += n;
+                normals[j1] += n;
+                // triangle j1, i0, j0
+                let n = (pi0 - pj1).cross(pj0 - pj1);
+                normals[j1] += n;
+                normals[j0] += n;
+                normals[i0] += n;
+            }
+        }
+        for n in normals.iter_mut() {
+            *n = n.normalize();
+        }
+        normals
+    }
+
+    ///
+    /// Returns the vertex tangents of the ribbon built from "paths", "uvs" and "normals".
+    /// Same algorithm than TriMesh::compute_tangents() computed over the ribbon grid.
+    ///
+    pub fn compute_ribbon_tangents(paths: &[Vec<Vec3>], uvs: &[Vec2], normals: &[Vec3]) -> Vec<Vec4> {
+        let p = paths.len();
+        let l = if p > 0 { paths[0].len() } else { 0 };
+        let mut tan1 = vec![Vec3::new(0.0, 0.0, 0.0); p * l];
+        let mut tan2 = vec![Vec3::new(0.0, 0.0, 0.0); p * l];
+        let mut add_triangle = |a: usize, b: usize, c: usize, pa: Vec3, pb: Vec3, pc: Vec3| {
+            let ba = pb - pa;
+            let ca = pc - pa;
+            let uvba = uvs[b] - uvs[a];
+            let uvca = uvs[c] - uvs[a];
+            let d = uvba.x * uvca.y - uvca.x * uvba.y;
+            if d.abs() > 0.00001 {
+                let r = 1.0 / d;
+                let sdir = (ba * uvca.y - ca * uvba.y) * r;
+                let tdir = (ca * uvba.x - ba * uvca.x) * r;
+                for index in [a, b, c] {
+                    tan1[index] += sdir;
+                    tan2[index] += tdir;
+                }
+            }
+        };
+        for i in 0..p.saturating_sub(1) {
+            for j in 0..l.saturating_sub(1) {
+                let i0 = i * l + j;
+                let i1 = i * l + j + 1;
+                let j0 = (i + 1) * l + j;
+                let j1 = (i + 1) * l + j + 1;
+                add_triangle(i0, j1, i1, paths[i][j], paths[i + 1][j + 1], paths[i][j + 1]);
+                add_triangle(j1, i0, j0, paths[i + 1][j + 1], paths[i][j], paths[i + 1][j]);
+            }
+        }
+
+        let mut tangents = vec![Vec4::new(0.0, 0.0, 0.0, 0.0); p * l];
+        for (index, tangent) in tangents.iter_mut().enumerate() {
+            let normal = normals[index];
+            let t = tan1[index];
+            let orthogonal = (t - normal * normal.dot(t)).normalize();
+            let handedness = if normal.cross(orthogonal).dot(tan2[index]) < 0.0 { 1.0 } else { -1.0 };
+            *tangent = orthogonal.extend(handedness);
+        }
+        tangents
+    }
+
+    ///
+    /// Updates the mesh vertex buffers from the passed paths, uvs and normals.
+    /// The paths must have the same dimensions than the ones the ribbon was created with.
+    /// Empty uvs or normals leave the current buffers unchanged.
+    /// If "update_normals" is true, the normals are recomputed for the new positions instead of being copied from "normals".
+    /// The tangents are recomputed from the new normals when uvs are given.
+    ///
+    pub fn morph_ribbon(mesh: &mut Mesh, paths: &[Vec<Vec3>], uvs: &[Vec2], normals: &[Vec3], update_normals: bool) {
         let mut positions = Vec::new();
-        for i in 0..paths.len() {
-            for j in 0..paths[i].len() {
-                let v3 = paths[i][j].clone();
-                positions.push(v3);
+        for path in paths {
+            for v3 in path {
+                positions.push(*v3);
             }
         }
         let vb_pos = mesh.positions_mut();
         vb_pos.fill(&positions);
-        if uvs.len() > 0 {
+        if !uvs.is_empty() {
             if let Some(vb_uvs) = mesh.uvs_mut()  {
-                vb_uvs.fill(&uvs);
+                // flipped in v like three-d does when the mesh is created from a CpuMesh
+                let flipped_uvs: Vec<Vec2> = uvs.iter().map(|uv| vec2(uv.x, 1.0 - uv.y)).collect();
+                vb_uvs.fill(&flipped_uvs);
+            }
+        }
+        if update_normals {
+            let normals = compute_ribbon_normals(paths);
+            if !uvs.is_empty() {
+                if let Some(vb_tangents) = mesh.tangents_mut() {
+                    let tangents = compute_ribbon_tangents(paths, uvs, &normals);
+                    vb_tangents.fill(&tangents);
+                }
+            }
+            if let Some(vb_normals) = mesh.normals_mut() {
+                vb_normals.fill(&normals);
+            }
+        }
+        else if !normals.is_empty() {
+            // the tangents follow the given normals, else they're left from the previous vertices
+            if !uvs.is_empty() {
+                if let Some(vb_tangents) = mesh.tangents_mut() {
+                    let tangents = compute_ribbon_tangents(paths, uvs, normals);
+                    vb_tangents.fill(&tangents);
+                }
+            }
+            if let Some(vb_normals) = mesh.normals_mut() {
+                vb_normals.fill(normals);
             }
         }
     }
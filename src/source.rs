@@ -0,0 +1,275 @@
+pub mod source {
+
+    use three_d::{vec3, InnerSpace, Srgba, Vec2, Vec3};
+
+    ///
+    /// How a finite height source is extended beyond its edges.
+    ///
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum WrapMode {
+        Repeat,     // the source repeats itself, the cells beyond an edge are the ones from the opposite edge
+        Clamp,      // the cells beyond an edge are the ones on the edge
+    }
+
+    ///
+    /// Elevation data sampled by the terrain, by absolute integer cells: i on the z axis and j on the x axis.
+    /// Any cell is valid: a finite source is extended beyond its edges by its wrap mode, an endless one is computed on demand.
+    /// The map coordinates of the cell (i, j) are cell_origin() + (j * spacing.x, i * spacing.y).
+    ///
+    pub trait HeightSource {
+        /// Elevation of the cell (i, j).
+        fn height_at_cell(&self, i: i32, j: i32) -> f32;
+
+        /// Texture coordinates of the cell (i, j).
+        fn uv_at_cell(&self, i: i32, j: i32) -> Vec2;
+
+        /// Distance between two successive cells on the x axis (x) and on the z axis (y).
+        fn spacing(&self) -> Vec2;
+
+        /// Map coordinates of the cell (0, 0) on the x axis (x) and on the z axis (y).
+        fn cell_origin(&self) -> Vec2;
+
+        /// Number of cells (width, depth) on the x and z axis of a finite source, None for an endless source.
+        fn dimensions(&self) -> Option<(usize, usize)> {
+            None
+        }
+
+        /// How a finite source is extended beyond its edges.
+        fn wrap_mode(&self) -> WrapMode {
+            WrapMode::Repeat
+        }
+
+        /// Whether the source gives precomputed normals, else the terrain computes them from the heights.
+        fn has_normals(&self) -> bool {
+            false
+        }
+
+        /// Upward normal of the cell (i, j), only used if has_normals() is true.
+        fn normal_at_cell(&self, _i: i32, _j: i32) -> Vec3 {
+            vec3(0.0, 1.0, 0.0)
+        }
+
+        /// Whether the source gives vertex colors.
+        fn has_colors(&self) -> bool {
+            false
+        }
+
+        /// Color of the cell (i, j), only used if has_colors() is true.
+        fn color_at_cell(&self, _i: i32, _j: i32) -> Srgba {
+            Srgba::WHITE
+        }
+
+        /// Returns the cell of the source data giving the cell (i, j), according to the wrap mode.
+        /// The cells of an endless source are returned unchanged.
+        fn wrap_cell(&self, i: i32, j: i32) -> (i32, i32) {
+            match (self.dimensions(), self.wrap_mode()) {
+                (Some((width, depth)), WrapMode::Repeat) => (i.rem_euclid(depth as i32), j.rem_euclid(width as i32)),
+                (Some((width, depth)), WrapMode::Clamp) => (i.clamp(0, depth as i32 - 1), j.clamp(0, width as i32 - 1)),
+                (None, _) => (i, j),
+            }
+        }
+
+        /// Returns the altitude at the map coordinates (x, z).
+        fn get_height_at(&self, x: f32, z: f32) -> f32 {
+            self.get_height_and_normal_at(x, z).0
+        }
+
+        /// Returns the altitude and the upward normal at the map coordinates (x, z).
+        /// The value is interpolated within the quad triangle containing the point, split like in create_ribbon().
+        fn get_height_and_normal_at(&self, x: f32, z: f32) -> (f32, Vec3) {
+            let spacing = self.spacing();
+            let corner = self.cell_origin();
+            let fx = (x - corner.x) / spacing.x;
+            let fz = (z - corner.y) / spacing.y;
+            let cell_x = fx.floor();
+            let cell_z = fz.floor();
+            let tx = fx - cell_x;
+            let tz = fz - cell_z;
+            let (i, j) = (cell_z as i32, cell_x as i32);
+            let h00 = self.height_at_cell(i, j);
+            let h01 = self.height_at_cell(i, j + 1);
+            let h10 = self.height_at_cell(i + 1, j);
+            let h11 = self.height_at_cell(i + 1, j + 1);
+            // slopes of the triangle along x and z
+            let (dx, dz) = if tx >= tz {
+                (h01 - h00, h11 - h01)
+            } else {
+                (h11 - h10, h10 - h00)
+            };
+            let altitude = h00 + tx * dx + tz * dz;
+            let normal = vec3(-dx / spacing.x, 1.0, -dz / spacing.y).normalize();
+            (altitude, normal)
+        }
+
+        /// Casts a ray in map coordinates and returns the first hit on the surface within max_dist.
+        /// The cells crossed by the ray are walked in order (DDA) and their two triangles are tested.
+        /// The source is unbounded, so max_dist must be finite: an infinite or NaN distance returns None.
+        fn intersect_ray(&self, origin: Vec3, direction: Vec3, max_dist: f32) -> Option<RayHit> {
+            if direction.magnitude2() == 0.0 || !max_dist.is_finite() {
+                return None;
+            }
+            let dir = direction.normalize();
+            let corner = self.cell_origin();
+            let x0 = corner.x;
+            let z0 = corner.y;
+            let sx = self.spacing().x;
+            let sz = self.spacing().y;
+
+            // current cell and DDA steps
+            let fx = (origin.x - x0) / sx;
+            let fz = (origin.z - z0) / sz;
+            let mut cell_x = fx.floor() as i32;
+            let mut cell_z = fz.floor() as i32;
+            let step_x = if dir.x > 0.0 { 1 } else { -1 };
+            let step_z = if dir.z > 0.0 { 1 } else { -1 };
+            let delta_t_x = if dir.x != 0.0 { (sx / dir.x).abs() } else { f32::INFINITY };
+            let delta_t_z = if dir.z != 0.0 { (sz / dir.z).abs() } else { f32::INFINITY };
+            let mut t_max_x = if dir.x > 0.0 { (cell_x as f32 + 1.0 - fx) * delta_t_x } else if dir.x < 0.0 { (fx - cell_x as f32) * delta_t_x } else { f32::INFINITY };
+            let mut t_max_z = if dir.z > 0.0 { (cell_z as f32 + 1.0 - fz) * delta_t_z } else if dir.z < 0.0 { (fz - cell_z as f32) * delta_t_z } else { f32::INFINITY };
+
+            let mut t_enter = 0.0;
+            while t_enter <= max_dist {
+                // cell corners, unwrapped so they stay next to the ray
+                let x = x0 + cell_x as f32 * sx;
+                let z = z0 + cell_z as f32 * sz;
+                let p00 = vec3(x, self.height_at_cell(cell_z, cell_x), z);
+                let p01 = vec3(x + sx, self.height_at_cell(cell_z, cell_x + 1), z);
+                let p10 = vec3(x, self.height_at_cell(cell_z + 1, cell_x), z + sz);
+                let p11 = vec3(x + sx, self.height_at_cell(cell_z + 1, cell_x + 1), z + sz);
+                // same triangles than create_ribbon()
+                let hits = [
+                    intersect_triangle(origin, dir, p00, p01, p11).map(|t| (t, (p01 - p00).cross(p11 - p00))),
+                    intersect_triangle(origin, dir, p11, p10, p00).map(|t| (t, (p10 - p11).cross(p00 - p11))),
+                ];
+                let nearest = hits.iter().flatten().filter(|(t, _)| *t <= max_dist).min_by(|a, b| a.0.total_cmp(&b.0));
+                if let Some((t, n)) = nearest {
+                    let normal = if n.y < 0.0 { -n.normalize() } else { n.normalize() };
+                    let (map_i, map_j) = self.wrap_cell(cell_z, cell_x);
+                    return Some(RayHit {
+                        point: origin + dir * *t,
+                        normal,
+                        distance: *t,
+                        map_i,
+                        map_j,
+                    });
+                }
+                // next cell
+                if t_max_x < t_max_z {
+                    t_enter = t_max_x;
+                    t_max_x += delta_t_x;
+                    cell_x += step_x;
+                } else {
+                    t_enter = t_max_z;
+                    t_max_z += delta_t_z;
+                    cell_z += step_z;
+                }
+            }
+            None
+        }
+    }
+
+    ///
+    /// Result of a ray cast on a height source or on the terrain.
+    ///
+    pub struct RayHit {
+        pub point: Vec3,        // hit point, in the coordinates of the ray
+        pub normal: Vec3,       // upward normal of the hit triangle
+        pub distance: f32,      // distance from the ray origin
+        pub map_i: i32,         // cell row (z axis) of the hit, wrapped into the source data
+        pub map_j: i32,         // cell column (x axis) of the hit, wrapped into the source data
+    }
+
+    // returns the distance along the ray to the triangle (a, b, c), whatever its orientation (Möller–Trumbore)
+    fn intersect_triangle(origin: Vec3, dir: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Option<f32> {
+        let epsilon = 1e-7;
+        let tolerance = 1e-5;           // accepts hits on the shared edges despite rounding errors
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let h = dir.cross(edge2);
+        let det = edge1.dot(h);
+        if det.abs() < epsilon {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = origin - a;
+        let u = inv_det * s.dot(h);
+        if u < -tolerance || u > 1.0 + tolerance {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = inv_det * dir.dot(q);
+        if v < -tolerance || u + v > 1.0 + tolerance {
+            return None;
+        }
+        let t = inv_det * edge2.dot(q);
+        if t >= 0.0 { Some(t) } else { None }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use three_d::{vec2, vec3};
+
+        // 3 x 2 cells source, one unit apart, the height of the cell (i, j) is 10 * i + j
+        struct Grid {
+            wrap: WrapMode,
+        }
+
+        impl HeightSource for Grid {
+            fn height_at_cell(&self, i: i32, j: i32) -> f32 {
+                let (i, j) = self.wrap_cell(i, j);
+                (10 * i + j) as f32
+            }
+
+            fn uv_at_cell(&self, i: i32, j: i32) -> Vec2 {
+                vec2(j as f32 / 3.0, 1.0 - i as f32 / 2.0)
+            }
+
+            fn spacing(&self) -> Vec2 {
+                vec2(1.0, 1.0)
+            }
+
+            fn cell_origin(&self) -> Vec2 {
+                vec2(0.0, 0.0)
+            }
+
+            fn dimensions(&self) -> Option<(usize, usize)> {
+                Some((3, 2))
+            }
+
+            fn wrap_mode(&self) -> WrapMode {
+                self.wrap
+            }
+        }
+
+        #[test]
+        fn wrap_cell_follows_the_wrap_mode() {
+            let repeat = Grid { wrap: WrapMode::Repeat };
+            assert_eq!(repeat.wrap_cell(-1, 3), (1, 0));
+            assert_eq!(repeat.wrap_cell(5, -4), (1, 2));
+            let clamp = Grid { wrap: WrapMode::Clamp };
+            assert_eq!(clamp.wrap_cell(-1, 3), (0, 2));
+            assert_eq!(clamp.wrap_cell(5, -4), (1, 0));
+        }
+
+        #[test]
+        fn heights_are_interpolated_beyond_the_edges() {
+            let repeat = Grid { wrap: WrapMode::Repeat };
+            // between the last column and the first one
+            assert_eq!(repeat.get_height_at(2.5, 0.0), 1.0);
+            let clamp = Grid { wrap: WrapMode::Clamp };
+            assert_eq!(clamp.get_height_at(2.5, 0.0), 2.0);
+            assert_eq!(clamp.get_height_at(0.5, 0.5), 5.5);
+        }
+
+        #[test]
+        fn rays_need_a_finite_distance() {
+            let grid = Grid { wrap: WrapMode::Repeat };
+            let hit = grid.intersect_ray(vec3(0.5, 50.0, 0.5), vec3(0.0, -1.0, 0.0), 100.0).unwrap();
+            assert!((hit.point.y - 5.5).abs() < 1e-4);
+            // an unbounded ray above the surface would be walked forever
+            assert!(grid.intersect_ray(vec3(0.5, 50.0, 0.5), vec3(1.0, 0.0, 0.0), f32::INFINITY).is_none());
+            assert!(grid.intersect_ray(vec3(0.5, 50.0, 0.5), vec3(0.0, -1.0, 0.0), f32::NAN).is_none());
+        }
+    }
+}
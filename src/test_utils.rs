@@ -0,0 +1,7 @@
+pub mod test_utils {
+
+    // path of a scratch file in the temporary directory, unique to the test process
+    pub fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("dynamic_terrain_{}_{}", std::process::id(), name)).to_string_lossy().into_owned()
+    }
+}
@@ -5,7 +5,7 @@ pub mod wireframe {
     pub fn apply_wireframe(context :&Context, cpu_mesh :&CpuMesh) -> Gm<Box<InstancedMesh>, Box<PhysicalMaterial>> {
 
         let mut wireframe_material = PhysicalMaterial::new_opaque(
-            &context,
+            context,
             &CpuMaterial {
                 albedo: Srgba::new_opaque(255, 200, 200),
                 roughness: 0.7,
@@ -18,11 +18,10 @@ pub mod wireframe {
     cylinder
         .transform(Mat4::from_nonuniform_scale(1.0, 0.01, 0.01))
         .unwrap();
-    let edges = Gm::new(
-        Box::new(InstancedMesh::new(&context, &edge_transformations(&cpu_mesh), &cylinder)),
+    Gm::new(
+        Box::new(InstancedMesh::new(context, &edge_transformations(cpu_mesh), &cylinder)),
         Box::new(wireframe_material.clone()),
-    );
-    edges
+    )
     }
 
     fn edge_transformations(cpu_mesh: &CpuMesh) -> Instances {